@@ -7,8 +7,40 @@ pub struct PacketLog {
     pub ipv4_destination: u32,
     pub action: XdpAction,
     pub packet_type: PacketType,
+    pub reason: Reason,
+    pub rule_id: u32,
+    pub vlan_id: u16,
+    // When a tunnel was looked through, the addresses above are the inner ones
+    // and these are the outer header's
+    pub tunnel: TunnelType,
+    pub outer_source: u32,
+    pub outer_destination: u32,
+    pub icmp_type: u8,
+    pub icmp_code: u8,
+    // 0 unless TCP or UDP, and for later fragments
+    pub source_port: u16,
+    pub destination_port: u16,
+    // The event stands for this many packets
+    pub sample_rate: u32,
 }
 
+// Value of the rule tables, rule_id is reported back in the PacketLog
+// of every packet the entry matches. port is the REDIRECT_PORTS entry
+// packets go out of when the action is REDIRECT
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct Rule {
+    pub action: XdpAction,
+    pub rule_id: u32,
+    pub port: u32,
+}
+
+pub const REDIRECT_PORTS: u32 = 64;
+// Port of REDIRECT rules that deliver packets to the AF_XDP socket of their receive queue
+pub const XSK_PORT: u32 = u32::MAX;
+// Receive queues that can have an AF_XDP socket
+pub const XSK_QUEUES: u32 = 64;
+
 #[derive(Clone, Copy, PartialEq)]
 #[repr(u8)]
 pub enum PacketType {
@@ -18,7 +50,7 @@ pub enum PacketType {
     UNKNOW,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 #[repr(u32)]
 pub enum XdpAction {
     ABORTED = 0,
@@ -28,5 +60,393 @@ pub enum XdpAction {
     REDIRECT = 4,
 }
 
+// Key of the ICMP_POLICY map, code ICMP_ANY_CODE matches every code of the type
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct IcmpKey {
+    pub icmp_type: u8,
+    pub code: u8,
+}
+
+pub const ICMP_ANY_CODE: u8 = 0xff;
+pub const ICMP_TYPES: u32 = 256;
+
+// Key of the SERVICES map, port in host order
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct ServiceKey {
+    pub vip: u32,
+    pub port: u16,
+    pub protocol: u8,
+    pub _pad: u8,
+}
+
+// How packets reach a load balancer backend
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u32)]
+pub enum ForwardMode {
+    // Rewrite the destination MAC, the backend has the VIP configured itself
+    MAC = 0,
+    // Encapsulate in an outer IPv4 header addressed to the backend
+    IPIP = 1,
+}
+
+// Value of the SERVICES map. id selects the service's rows of the MAGLEV
+// table and its BACKENDS slots
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct Service {
+    pub id: u32,
+    pub forward: ForwardMode,
+}
+
+// Entry of the BACKENDS array, mac is the next hop and source_mac the
+// address of the interface the packet leaves through
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct Backend {
+    pub address: u32,
+    // REDIRECT_PORTS entry, or BACKEND_TX to send back out of the receiving interface
+    pub port: u32,
+    pub mac: [u8; 6],
+    pub source_mac: [u8; 6],
+}
+
+pub const BACKEND_TX: u32 = u32::MAX;
+pub const LB_SERVICES: u32 = 64;
+pub const LB_BACKENDS: u32 = 32;
+// Rows of the MAGLEV table per service, a prime well above LB_BACKENDS
+pub const MAGLEV_SIZE: u32 = 4099;
+
+// Key of the DNAT and SNAT maps, the address and port being translated. Port
+// forwards set the port (host order) and protocol, 1:1 NAT leaves both 0
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct NatKey {
+    pub address: u32,
+    pub port: u16,
+    pub protocol: u8,
+    pub _pad: u8,
+}
+
+// What a NatKey is translated to, port is only used by port forwards
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct NatTarget {
+    pub address: u32,
+    pub port: u16,
+    pub _pad: u16,
+}
+
+pub const NAT_ENTRIES: u32 = 1024;
+
+// Key of the AGGREGATES map, packets are counted per flow and action
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct AggregateKey {
+    pub source: u32,
+    pub destination: u32,
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub packet_type: PacketType,
+    pub _pad: [u8; 3],
+    pub action: XdpAction,
+}
+
+impl AggregateKey {
+    // The flow and action of a packet, as the XDP program counts it
+    pub fn new(log_entry: &PacketLog) -> Self {
+        AggregateKey {
+            source: log_entry.ipv4_address,
+            destination: log_entry.ipv4_destination,
+            source_port: log_entry.source_port,
+            destination_port: log_entry.destination_port,
+            packet_type: log_entry.packet_type,
+            _pad: [0; 3],
+            action: log_entry.action,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct AggregateValue {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+pub const AGGREGATE_ENTRIES: u32 = 16384;
+
+// Sent on SFLOW_SAMPLES, followed by the first header_length bytes of the frame
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct SflowSample {
+    pub frame_length: u32,
+    pub header_length: u32,
+    pub action: XdpAction,
+    pub sample_rate: u32,
+}
+
+// Sources each rule table holds. The table in use is the only entry of the
+// RULE_TABLES map, which userspace points at a new table to swap them
+pub const RULE_ENTRIES: u32 = 1024;
+
+// Prefixes each BLOCKLIST trie holds
+pub const BLOCKLIST_ENTRIES: u32 = 262144;
+
+// Bytes of the frame copied into a sample, the sFlow default
+pub const SFLOW_HEADER: u32 = 128;
+
+// Two ACTION_COUNTERS slots per XdpAction, packets then bytes
+pub const ACTION_COUNTER_SLOTS: u32 = 10;
+
+// A port knocking gate, entry of the KNOCK_GATES array. A source that sends to the
+// first steps ports in order, each within timeout of the one before, may reach the
+// protected port until it sends nothing to it for open nanoseconds. Ports are in
+// host order
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct KnockGate {
+    pub ports: [u16; KNOCK_STEPS],
+    pub steps: u32,
+    // IPPROTO_TCP or IPPROTO_UDP of the knocks and of the protected port
+    pub knock_protocol: u8,
+    pub protocol: u8,
+    pub port: u16,
+    pub timeout: u64,
+    pub open: u64,
+}
+
+// Key of the KNOCK_STATE map, the gate is its index in KNOCK_GATES
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct KnockKey {
+    pub source: u32,
+    pub gate: u32,
+}
+
+// How far a source got through a sequence. The deadline is when the next knock is
+// due, or when the gate closes again once every step is done
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct KnockState {
+    pub step: u32,
+    pub _pad: u32,
+    pub deadline: u64,
+}
+
+pub const KNOCK_STEPS: usize = 8;
+pub const KNOCK_GATES: u32 = 8;
+pub const KNOCK_SOURCES: u32 = 65536;
+
+// Key of the DETECT_PORTS and DETECT_HOSTS maps, a destination port or host a
+// source reached. Their value is when it last did, in bpf_ktime_get_ns time
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct ReachKey {
+    pub source: u32,
+    pub target: u32,
+}
+
+// What the XDP program counts per source for the detectors, enabled in the
+// CONFIG_DETECT bitmask
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u32)]
+pub enum DetectCount {
+    PACKETS = 0,
+    PORTS = 1,
+    HOSTS = 2,
+}
+
+impl DetectCount {
+    pub const fn mask(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+pub const DETECT_SOURCES: u32 = 65536;
+pub const DETECT_TARGETS: u32 = 262144;
+
+// Key of the VLAN_ACTION_LIST map, for rules that only apply on one VLAN
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct VlanKey {
+    pub vlan_id: u32,
+    pub source: u32,
+}
+
+// Why the XDP program picked the action in a PacketLog
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Reason {
+    NONE,
+    LISTED,
+    BOGON,
+    MALFORMED,
+    FRAGMENT,
+    ICMP,
+    // Echo request answered by the XDP program (TX) or over the rate limit (DROP)
+    ECHO,
+    // Sent to a load balancer backend, the rule_id is the service id
+    SERVICE,
+    // Source in an imported blocklist, the rule_id is the list id
+    BLOCKLIST,
+    // A knock, or the protected port of a gate that isn't open. The rule_id is the
+    // gate id for this and the knock reasons below
+    KNOCK,
+    // The last knock of the sequence, the gate is open for the source
+    UNLOCKED,
+    // A knock out of order or after the timeout, the sequence starts over
+    MISKNOCK,
+}
+
+// Which bogon list a source address matched, used as the BOGONS map value
+// and as the index into BOGON_COUNTERS
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u32)]
+pub enum BogonType {
+    PRIVATE = 0,
+    LOOPBACK = 1,
+    MULTICAST = 2,
+    THISNET = 3,
+    RESERVED = 4,
+    LOCAL = 5,
+}
+
+pub const BOGON_TYPES: u32 = 6;
+
+// Header sanity checks, a MALFORMED PacketLog carries the failed check as its rule_id
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u32)]
+pub enum Check {
+    TRUNCATED = 0,
+    VERSION = 1,
+    IHL = 2,
+    LENGTH = 3,
+    FRAGMENT = 4,
+    TCPFLAGS = 5,
+    OPTIONS = 6,
+}
+
+impl Check {
+    // Bit of the check in the CONFIG_CHECKS bitmask
+    pub const fn mask(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+// Fragment policies, fragments pass unless one is set in the CONFIG_FRAGMENTS
+// bitmask. A FRAGMENT PacketLog carries the policy that dropped it as its rule_id
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u32)]
+pub enum FragmentPolicy {
+    DROP = 0,
+    TINY = 1,
+    OVERLAP = 2,
+}
+
+impl FragmentPolicy {
+    pub const fn mask(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+// Indexes of the FRAGMENT_COUNTERS map
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u32)]
+pub enum FragmentCount {
+    FIRST = 0,
+    LATER = 1,
+    DROPPED = 2,
+    TINY = 3,
+    OVERLAP = 4,
+}
+
+pub const FRAGMENT_COUNTS: u32 = 5;
+
+// Indexes of the ECHO_COUNTERS map
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u32)]
+pub enum EchoCount {
+    REPLIED = 0,
+    LIMITED = 1,
+}
+
+pub const ECHO_COUNTS: u32 = 2;
+
+// Encapsulations the XDP program can look through, enabled in the CONFIG_TUNNELS bitmask
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum TunnelType {
+    NONE = 0,
+    IPIP = 1,
+    GRE = 2,
+    VXLAN = 3,
+}
+
+impl TunnelType {
+    pub const fn mask(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+// Slots of the CONFIG array map, written by userspace at startup
+pub const CONFIG_CHECKS: u32 = 0;
+pub const CONFIG_FRAGMENTS: u32 = 1;
+pub const CONFIG_TUNNELS: u32 = 2;
+// Echo replies a second, 0 leaves echo requests to the kernel stack
+pub const CONFIG_ECHO_RATE: u32 = 3;
+// Source address of IPIP packets sent to load balancer backends
+pub const CONFIG_LB_SOURCE: u32 = 4;
+// Non zero to count packets in the AGGREGATES map
+pub const CONFIG_AGGREGATE: u32 = 5;
+// 1 in N packets are sampled for sFlow, 0 turns sFlow off
+pub const CONFIG_SFLOW_RATE: u32 = 6;
+// Which BLOCKLIST trie lookups use: 0 for none, 1 + the trie's index
+pub const CONFIG_BLOCKLIST: u32 = 7;
+// First of the event sample rates, one slot per XdpAction
+pub const CONFIG_SAMPLE_RATES: u32 = 8;
+// Gates in the KNOCK_GATES array, 0 turns port knocking off
+pub const CONFIG_KNOCK_GATES: u32 = 13;
+// DetectCount bitmask, 0 turns the per source counters off
+pub const CONFIG_DETECT: u32 = 14;
+pub const CONFIG_SIZE: u32 = 16;
+
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for PacketLog {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Rule {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for VlanKey {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for IcmpKey {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ServiceKey {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Service {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Backend {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for NatKey {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for NatTarget {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for AggregateKey {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for AggregateValue {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KnockGate {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ReachKey {}
@@ -1,23 +1,74 @@
 #![no_std]
 #![no_main]
 mod bindings;
+mod maps;
 use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use aya_bpf::{
-    bindings::xdp_action,
-    macros::{map, xdp},
-    maps::{HashMap, PerfEventArray},
-    programs::XdpContext,
+    bindings::{xdp_action, BPF_F_MARK_MANGLED_0, BPF_F_NO_PREALLOC, BPF_F_PSEUDO_HDR, TC_ACT_OK},
+    helpers::{bpf_get_prandom_u32, bpf_ktime_get_ns, bpf_xdp_adjust_head},
+    macros::{classifier, map, xdp},
+    maps::{lpm_trie::Key, Array, HashMap, LpmTrie, LruHashMap, PerCpuArray, PerfEventArray},
+    programs::{SkBuffContext, XdpContext},
 };
-use bindings::{ethhdr, iphdr};
-use ebpfapp_common::{PacketLog, PacketType, XdpAction};
+use bindings::{ethhdr, icmphdr, iphdr, tcphdr, udphdr};
+use ebpfapp_common::{
+    AggregateKey, AggregateValue, Backend, BogonType, Check, DetectCount, EchoCount, ForwardMode,
+    FragmentCount, FragmentPolicy, IcmpKey, KnockGate, KnockKey, KnockState, NatKey, NatTarget,
+    PacketLog, PacketType, ReachKey, Reason, Rule, Service, ServiceKey, SflowSample, TunnelType,
+    VlanKey, XdpAction, ACTION_COUNTER_SLOTS, AGGREGATE_ENTRIES, BACKEND_TX, BLOCKLIST_ENTRIES,
+    BOGON_TYPES, CONFIG_AGGREGATE, CONFIG_BLOCKLIST, CONFIG_CHECKS, CONFIG_DETECT,
+    CONFIG_ECHO_RATE, CONFIG_FRAGMENTS, CONFIG_KNOCK_GATES, CONFIG_LB_SOURCE, CONFIG_SAMPLE_RATES,
+    CONFIG_SFLOW_RATE, CONFIG_SIZE, CONFIG_TUNNELS, DETECT_SOURCES, DETECT_TARGETS, ECHO_COUNTS,
+    FRAGMENT_COUNTS, ICMP_ANY_CODE, ICMP_TYPES, KNOCK_GATES, KNOCK_SOURCES, KNOCK_STEPS,
+    LB_BACKENDS, LB_SERVICES, MAGLEV_SIZE, NAT_ENTRIES, REDIRECT_PORTS, SFLOW_HEADER, XSK_PORT,
+    XSK_QUEUES,
+};
+use maps::{inner_get, ArrayOfMaps, DevMap, XskMap};
 use memoffset::offset_of;
 
 const IPPROTO_TCP: u8 = 6;
 const IPPROTO_UDP: u8 = 17;
 const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_IPIP: u8 = 4;
+const IPPROTO_GRE: u8 = 47;
 const ETH_P_IP: u16 = 0x0800;
+const ETH_P_8021Q: u16 = 0x8100;
+const ETH_P_8021AD: u16 = 0x88A8;
+// Transparent ethernet bridging, GRE carrying whole ethernet frames
+const ETH_P_TEB: u16 = 0x6558;
 const ETH_HDR_LEN: usize = mem::size_of::<ethhdr>();
+const VLAN_HDR_LEN: usize = mem::size_of::<VlanHdr>();
+const VLAN_VID_MASK: u16 = 0x0fff;
+// 802.1ad (QinQ) stacks at most a service tag on top of a customer tag
+const VLAN_MAX_DEPTH: usize = 2;
+const IP_HDR_LEN: usize = mem::size_of::<iphdr>();
+// frag_off flag bits, the rest of the field is the fragment offset
+const IP_RF: u16 = 0x8000;
+const IP_DF: u16 = 0x4000;
+const IP_MF: u16 = 0x2000;
+const IP_OFFSET: u16 = 0x1fff;
+const TCP_HDR_LEN: usize = mem::size_of::<tcphdr>();
+const UDP_HDR_LEN: usize = 8;
+const ICMP_HDR_LEN: usize = 8;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+// TTL of the echo replies the XDP program sends
+const ECHO_TTL: u8 = 64;
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+// GRE base header is flags and protocol, each optional field adds 4 bytes
+const GRE_HDR_LEN: usize = 4;
+const GRE_CSUM: u16 = 0x8000;
+const GRE_KEY: u16 = 0x2000;
+const GRE_SEQ: u16 = 0x1000;
+const GRE_VERSION: u16 = 0x0007;
+const VXLAN_PORT: u16 = 4789;
+const VXLAN_HDR_LEN: usize = 8;
+// TTL of the outer header of IPIP packets sent to backends
+const IPIP_TTL: u8 = 64;
+// Ranges remembered per datagram for overlap detection
+const FRAGMENT_RANGES: usize = 4;
 
 #[inline(always)] // Inline due to limited support for function calls in ebpf programs
 unsafe fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Result<*const T, ()> {
@@ -33,75 +84,1338 @@ unsafe fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Result<*const T, ()> {
     Ok((start + offset) as *const T)
 }
 
+#[inline(always)]
+unsafe fn ptr_at_mut<T>(ctx: &XdpContext, offset: usize) -> Result<*mut T, ()> {
+    Ok(ptr_at::<T>(ctx, offset)? as *mut T)
+}
+
+// Offset of the checksum in the TCP or UDP header
+#[inline(always)]
+fn l4_checksum_offset(ip_proto: u8) -> Option<usize> {
+    match ip_proto {
+        IPPROTO_TCP => Some(offset_of!(tcphdr, check)),
+        IPPROTO_UDP => Some(offset_of!(udphdr, check)),
+        _ => None,
+    }
+}
+
+// RFC 1624 incremental update of a ones' complement checksum after one 16 bit word
+// of the data changed from old to new, everything in host order
+#[inline(always)]
+fn csum_replace(check: u16, old: u16, new: u16) -> u16 {
+    let mut sum = !check as u32 + !old as u32 + new as u32;
+    sum = (sum & 0xffff) + (sum >> 16);
+    sum = (sum & 0xffff) + (sum >> 16);
+    !(sum as u16)
+}
+
+// 802.1Q tag, the kernel headers don't export struct vlan_hdr
+#[repr(C)]
+pub struct VlanHdr {
+    tci: u16,
+    encapsulated_proto: u16,
+}
+
+pub struct Ethernet {
+    // Offset of the IPv4 header, past any VLAN tags
+    l3_offset: usize,
+    vlan_id: u16,
+}
+
+#[derive(Clone, Copy)]
 pub struct IPV4 {
     source: u32,
     destination: u32,
     protocol: PacketType,
+    ip_proto: u8,
+    id: u16,
+    frag_off: u16,
+    header_len: usize,
+    total_len: usize,
+    l4_offset: usize,
+    vlan_id: u16,
+    // Type and code, only for the first fragment of an ICMP packet
+    icmp: Option<IcmpKey>,
+    // Only for the first fragment of a TCP or UDP packet, 0 otherwise
+    source_port: u16,
+    destination_port: u16,
+    // Set once the header has been found inside a tunnel
+    tunnel: TunnelType,
+    outer_source: u32,
+    outer_destination: u32,
+}
+
+impl IPV4 {
+    // Placeholder for packets too short to parse, so they can still be logged
+    fn unparsed(eth: &Ethernet) -> IPV4 {
+        IPV4 {
+            source: 0,
+            destination: 0,
+            protocol: PacketType::UNKNOW,
+            ip_proto: 0,
+            id: 0,
+            frag_off: 0,
+            header_len: 0,
+            total_len: 0,
+            l4_offset: eth.l3_offset,
+            vlan_id: eth.vlan_id,
+            icmp: None,
+            source_port: 0,
+            destination_port: 0,
+            tunnel: TunnelType::NONE,
+            outer_source: 0,
+            outer_destination: 0,
+        }
+    }
+}
+
+// Identifies the datagram a fragment belongs to
+#[repr(C)]
+pub struct FragmentKey {
+    source: u32,
+    destination: u32,
+    id: u16,
+    protocol: u8,
+    _pad: u8,
+}
+
+// Byte ranges of the fragments seen so far, next is the slot to overwrite
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct FragmentRanges {
+    start: [u32; FRAGMENT_RANGES],
+    end: [u32; FRAGMENT_RANGES],
+    next: u32,
+}
+
+// Echo replies sent in the current one second window
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct EchoWindow {
+    start: u64,
+    replies: u32,
+    _pad: u32,
 }
 
 #[inline(always)]
-fn parse_ipv4(ctx: &XdpContext) -> Result<IPV4, ()> {
-    let source = u32::from_be(unsafe { *ptr_at(ctx, ETH_HDR_LEN + offset_of!(iphdr, saddr))? });
-    let destination =
-        u32::from_be(unsafe { *ptr_at(ctx, ETH_HDR_LEN + offset_of!(iphdr, daddr))? });
+fn parse_ipv4(ctx: &XdpContext, eth: &Ethernet) -> Result<IPV4, ()> {
+    let offset = eth.l3_offset;
+    let source = u32::from_be(unsafe { *ptr_at(ctx, offset + offset_of!(iphdr, saddr))? });
+    let destination = u32::from_be(unsafe { *ptr_at(ctx, offset + offset_of!(iphdr, daddr))? });
 
-    let protocol_type =
-        match u8::from_be(unsafe { *ptr_at(ctx, ETH_HDR_LEN + offset_of!(iphdr, protocol))? }) {
-            IPPROTO_TCP => PacketType::TCP,
-            IPPROTO_UDP => PacketType::UDP,
-            IPPROTO_ICMP => PacketType::ICMP,
-            _ => PacketType::UNKNOW,
-        };
+    let ip_proto = u8::from_be(unsafe { *ptr_at(ctx, offset + offset_of!(iphdr, protocol))? });
+    let protocol_type = match ip_proto {
+        IPPROTO_TCP => PacketType::TCP,
+        IPPROTO_UDP => PacketType::UDP,
+        IPPROTO_ICMP => PacketType::ICMP,
+        _ => PacketType::UNKNOW,
+    };
+    let id = u16::from_be(unsafe { *ptr_at(ctx, offset + offset_of!(iphdr, id))? });
+    let frag_off = u16::from_be(unsafe { *ptr_at(ctx, offset + offset_of!(iphdr, frag_off))? });
+    let total_len = u16::from_be(unsafe { *ptr_at(ctx, offset + offset_of!(iphdr, tot_len))? });
+    let version_ihl: u8 = unsafe { *ptr_at(ctx, offset)? };
+    let header_len = (version_ihl & 0x0f) as usize * 4;
+    let l4_offset = offset + header_len;
+
+    // Later fragments hold payload where the ICMP header would be
+    let icmp = if protocol_type == PacketType::ICMP && frag_off & IP_OFFSET == 0 {
+        unsafe { ptr_at::<icmphdr>(ctx, l4_offset) }
+            .ok()
+            .map(|icmp| unsafe {
+                IcmpKey {
+                    icmp_type: (*icmp).type_,
+                    code: (*icmp).code,
+                }
+            })
+    } else {
+        None
+    };
+    // TCP and UDP both start with the source and destination ports
+    let (source_port, destination_port) = match protocol_type {
+        PacketType::TCP | PacketType::UDP if frag_off & IP_OFFSET == 0 => (
+            unsafe { ptr_at::<u16>(ctx, l4_offset) }
+                .map_or(0, |port| u16::from_be(unsafe { *port })),
+            unsafe { ptr_at::<u16>(ctx, l4_offset + 2) }
+                .map_or(0, |port| u16::from_be(unsafe { *port })),
+        ),
+        _ => (0, 0),
+    };
 
     Ok(IPV4 {
         source,
         destination,
         protocol: protocol_type,
+        ip_proto,
+        id,
+        frag_off,
+        header_len,
+        total_len: total_len as usize,
+        l4_offset,
+        vlan_id: eth.vlan_id,
+        icmp,
+        source_port,
+        destination_port,
+        tunnel: TunnelType::NONE,
+        outer_source: 0,
+        outer_destination: 0,
     })
 }
 
 #[inline(always)]
-fn is_ipv4(ctx: &XdpContext) -> Result<bool, ()> {
+fn config(key: u32) -> u32 {
+    unsafe { CONFIG.get(key) }.copied().unwrap_or(0)
+}
+
+// Returns the first enabled check the packet fails, checks are a CONFIG_CHECKS bitmask
+#[inline(always)]
+fn sanity_check(ctx: &XdpContext, eth: &Ethernet, checks: u32) -> Result<Option<Check>, ()> {
+    let enabled = |check: Check| checks & check.mask() != 0;
+    let len = ctx.data_end() - ctx.data();
+    let offset = eth.l3_offset;
+
+    if offset + IP_HDR_LEN > len {
+        if enabled(Check::TRUNCATED) {
+            return Ok(Some(Check::TRUNCATED));
+        }
+        return Ok(None);
+    }
+    let ip: &iphdr = unsafe { &*ptr_at(ctx, offset)? };
+
+    if enabled(Check::VERSION) && ip.version() != 4 {
+        return Ok(Some(Check::VERSION));
+    }
+    let header_len = ip.ihl() as usize * 4;
+    if enabled(Check::IHL) && header_len < IP_HDR_LEN {
+        return Ok(Some(Check::IHL));
+    }
+    if enabled(Check::OPTIONS) && header_len > IP_HDR_LEN {
+        return Ok(Some(Check::OPTIONS));
+    }
+
+    let total_len = u16::from_be(ip.tot_len) as usize;
+    if enabled(Check::LENGTH) && total_len < header_len {
+        return Ok(Some(Check::LENGTH));
+    }
+    if enabled(Check::TRUNCATED) && offset + total_len > len {
+        return Ok(Some(Check::TRUNCATED));
+    }
+
+    // The reserved flag must be zero and a packet can't be both unfragmentable and a fragment
+    let frag_off = u16::from_be(ip.frag_off);
+    if enabled(Check::FRAGMENT)
+        && (frag_off & IP_RF != 0 || (frag_off & IP_DF != 0 && frag_off & (IP_MF | IP_OFFSET) != 0))
+    {
+        return Ok(Some(Check::FRAGMENT));
+    }
+
+    // Only the first fragment carries the TCP header
+    if enabled(Check::TCPFLAGS) && ip.protocol == IPPROTO_TCP && frag_off & IP_OFFSET == 0 {
+        let tcp: &tcphdr = match unsafe { ptr_at(ctx, offset + header_len) } {
+            Ok(tcp) => unsafe { &*tcp },
+            Err(_) if enabled(Check::TRUNCATED) => return Ok(Some(Check::TRUNCATED)),
+            Err(_) => return Ok(None),
+        };
+        let null = tcp.fin() == 0
+            && tcp.syn() == 0
+            && tcp.rst() == 0
+            && tcp.psh() == 0
+            && tcp.ack() == 0
+            && tcp.urg() == 0;
+        let xmas = tcp.fin() != 0 && tcp.psh() != 0 && tcp.urg() != 0;
+        let syn_fin = tcp.syn() != 0 && tcp.fin() != 0;
+        if null || xmas || syn_fin {
+            return Ok(Some(Check::TCPFLAGS));
+        }
+    }
+
+    Ok(None)
+}
+
+// Walks past up to VLAN_MAX_DEPTH 802.1Q/802.1ad tags, returns None if the frame isn't ipv4
+#[inline(always)]
+fn parse_ethernet(ctx: &XdpContext) -> Result<Option<Ethernet>, ()> {
     // Get protocol type of ethernet frame
-    let h_proto = u16::from_be(unsafe { *ptr_at(ctx, offset_of!(ethhdr, h_proto))? });
+    let mut h_proto = u16::from_be(unsafe { *ptr_at(ctx, offset_of!(ethhdr, h_proto))? });
+    let mut l3_offset = ETH_HDR_LEN;
+    let mut vlan_id = 0;
+
+    for depth in 0..VLAN_MAX_DEPTH {
+        if h_proto != ETH_P_8021Q && h_proto != ETH_P_8021AD {
+            break;
+        }
+        let vlan: &VlanHdr = unsafe { &*ptr_at(ctx, l3_offset)? };
+        // The outer tag is the VLAN the frame arrived on
+        if depth == 0 {
+            vlan_id = u16::from_be(vlan.tci) & VLAN_VID_MASK;
+        }
+        h_proto = u16::from_be(vlan.encapsulated_proto);
+        l3_offset += VLAN_HDR_LEN;
+    }
+
     // Check if it's ipv4, if isn't then allow it.
-    if h_proto == ETH_P_IP {
-        return Ok(true);
+    if h_proto != ETH_P_IP {
+        return Ok(None);
     }
-    Ok(false)
+    Ok(Some(Ethernet { l3_offset, vlan_id }))
 }
 
 #[inline(always)]
-fn generate_log(parsed_ipv4: IPV4, action: XdpAction) -> PacketLog {
+fn generate_log(parsed_ipv4: IPV4, action: XdpAction, reason: Reason, rule_id: u32) -> PacketLog {
     PacketLog {
         ipv4_address: parsed_ipv4.source,
         action,
         ipv4_destination: parsed_ipv4.destination,
         packet_type: parsed_ipv4.protocol,
+        reason,
+        rule_id,
+        vlan_id: parsed_ipv4.vlan_id,
+        tunnel: parsed_ipv4.tunnel,
+        outer_source: parsed_ipv4.outer_source,
+        outer_destination: parsed_ipv4.outer_destination,
+        icmp_type: parsed_ipv4.icmp.map_or(0, |icmp| icmp.icmp_type),
+        icmp_code: parsed_ipv4.icmp.map_or(0, |icmp| icmp.code),
+        source_port: parsed_ipv4.source_port,
+        destination_port: parsed_ipv4.destination_port,
+        sample_rate: 1,
     }
 }
 
-fn try_xdp_firewall(ctx: &XdpContext) -> Result<u32, ()> {
-    let is_ipv4 = is_ipv4(ctx)?;
-    if !is_ipv4 {
-        return Ok(xdp_action::XDP_PASS);
+// Counts the packet against its flow and action. Several cpus can hit the same
+// entry, so the counters are added to atomically
+#[inline(always)]
+fn aggregate(log_entry: &PacketLog, bytes: u64) {
+    let key = AggregateKey::new(log_entry);
+    match unsafe { AGGREGATES.get_mut(&key) } {
+        Some(value) => unsafe {
+            (*(&mut value.packets as *mut u64 as *const AtomicU64)).fetch_add(1, Ordering::Relaxed);
+            (*(&mut value.bytes as *mut u64 as *const AtomicU64))
+                .fetch_add(bytes, Ordering::Relaxed);
+        },
+        None => {
+            let value = AggregateValue { packets: 1, bytes };
+            let _ = unsafe { AGGREGATES.insert(&key, &value, 0) };
+        }
+    }
+}
+
+// Counts the frame for sFlow's interface counters under the verdict, and sends it
+// with the start of the frame appended when it was picked for a sample. len is the
+// length the frame arrived with, frames the program rewrote (NAT, echo replies,
+// load balanced packets) are sampled as they leave
+#[inline(always)]
+fn sflow(ctx: &XdpContext, ret: u32, len: u32, sampled: bool, rate: u32) {
+    let action = match ret {
+        xdp_action::XDP_DROP => XdpAction::DROP,
+        xdp_action::XDP_PASS => XdpAction::PASS,
+        xdp_action::XDP_TX => XdpAction::TX,
+        xdp_action::XDP_REDIRECT => XdpAction::REDIRECT,
+        _ => XdpAction::ABORTED,
+    };
+    let slot = action as u32 * 2;
+    if let Some(packets) = unsafe { ACTION_COUNTERS.get_mut(slot) } {
+        *packets += 1;
+    }
+    if let Some(bytes) = unsafe { ACTION_COUNTERS.get_mut(slot + 1) } {
+        *bytes += len as u64;
+    }
+    if !sampled {
+        return;
+    }
+    let available = (ctx.data_end() - ctx.data()) as u32;
+    let header_length = if available < SFLOW_HEADER {
+        available
+    } else {
+        SFLOW_HEADER
+    };
+    let sample = SflowSample {
+        frame_length: len,
+        header_length,
+        action,
+        sample_rate: rate,
+    };
+    // The upper 32 bits of the flags are the number of packet bytes to append
+    unsafe { SFLOW_SAMPLES.output(ctx, &sample, header_length) };
+}
+
+// Counts the packet in the aggregates, once userspace turned them on
+#[inline(always)]
+fn count(ctx: &XdpContext, log_entry: &PacketLog) {
+    if config(CONFIG_AGGREGATE) != 0 {
+        aggregate(log_entry, (ctx.data_end() - ctx.data()) as u64);
+    }
+}
+
+// Counts the packet and sends the event of 1 in N packets, N being the sample
+// rate configured for the action. A rate of 0 sends none
+#[inline(always)]
+fn emit(ctx: &XdpContext, mut log_entry: PacketLog) {
+    count(ctx, &log_entry);
+    let rate = config(CONFIG_SAMPLE_RATES + log_entry.action as u32);
+    if rate == 0 || (rate > 1 && unsafe { bpf_get_prandom_u32() } % rate != 0) {
+        return;
+    }
+    log_entry.sample_rate = rate;
+    unsafe { EVENTS.output(ctx, &log_entry, 0) };
+}
+
+// Offset of the IPv4 header inside an encapsulated ethernet frame
+#[inline(always)]
+fn inner_ethernet(ctx: &XdpContext, offset: usize) -> Option<usize> {
+    let h_proto = u16::from_be(unsafe { *ptr_at(ctx, offset + offset_of!(ethhdr, h_proto)).ok()? });
+    if h_proto != ETH_P_IP {
+        return None;
+    }
+    Some(offset + ETH_HDR_LEN)
+}
+
+// Finds the inner IPv4 header of an IPIP, GRE or VXLAN packet when that tunnel type is
+// enabled in the CONFIG_TUNNELS bitmask
+#[inline(always)]
+fn find_inner(
+    ctx: &XdpContext,
+    eth: &Ethernet,
+    outer: &IPV4,
+    tunnels: u32,
+) -> Option<(Ethernet, TunnelType)> {
+    // Only an unfragmented packet holds the whole encapsulation header
+    if outer.frag_off & (IP_MF | IP_OFFSET) != 0 {
+        return None;
+    }
+    let enabled = |tunnel: TunnelType| tunnels & tunnel.mask() != 0;
+    let l4_offset = outer.l4_offset;
+
+    let (l3_offset, tunnel) = match outer.ip_proto {
+        IPPROTO_IPIP if enabled(TunnelType::IPIP) => (l4_offset, TunnelType::IPIP),
+        IPPROTO_GRE if enabled(TunnelType::GRE) => {
+            let flags = u16::from_be(unsafe { *ptr_at(ctx, l4_offset).ok()? });
+            let proto = u16::from_be(unsafe { *ptr_at(ctx, l4_offset + 2).ok()? });
+            if flags & GRE_VERSION != 0 {
+                return None;
+            }
+            let mut offset = l4_offset + GRE_HDR_LEN;
+            if flags & GRE_CSUM != 0 {
+                offset += 4;
+            }
+            if flags & GRE_KEY != 0 {
+                offset += 4;
+            }
+            if flags & GRE_SEQ != 0 {
+                offset += 4;
+            }
+            match proto {
+                ETH_P_IP => (offset, TunnelType::GRE),
+                ETH_P_TEB => (inner_ethernet(ctx, offset)?, TunnelType::GRE),
+                _ => return None,
+            }
+        }
+        IPPROTO_UDP if enabled(TunnelType::VXLAN) => {
+            let dest =
+                u16::from_be(unsafe { *ptr_at(ctx, l4_offset + offset_of!(udphdr, dest)).ok()? });
+            if dest != VXLAN_PORT {
+                return None;
+            }
+            let offset = l4_offset + UDP_HDR_LEN + VXLAN_HDR_LEN;
+            (inner_ethernet(ctx, offset)?, TunnelType::VXLAN)
+        }
+        _ => return None,
+    };
+
+    Some((
+        Ethernet {
+            l3_offset,
+            vlan_id: eth.vlan_id,
+        },
+        tunnel,
+    ))
+}
+
+// The inner header of a tunnelled packet, the outer addresses are kept for the log
+#[inline(always)]
+fn decapsulate(
+    ctx: &XdpContext,
+    inner_eth: &Ethernet,
+    tunnel: TunnelType,
+    outer: &IPV4,
+) -> Option<IPV4> {
+    let mut inner = parse_ipv4(ctx, inner_eth).ok()?;
+    inner.tunnel = tunnel;
+    inner.outer_source = outer.source;
+    inner.outer_destination = outer.destination;
+    Some(inner)
+}
+
+// Rules scoped to the packet's VLAN take precedence over ones for every VLAN
+#[inline(always)]
+fn lookup_rule(parsed_ipv4: &IPV4) -> Option<Rule> {
+    if parsed_ipv4.vlan_id != 0 {
+        let key = VlanKey {
+            vlan_id: parsed_ipv4.vlan_id as u32,
+            source: parsed_ipv4.source,
+        };
+        if let Some(rule) = unsafe { VLAN_ACTION_LIST.get(&key) } {
+            return Some(*rule);
+        }
+    }
+    let table = unsafe { RULE_TABLES.get(0) }?;
+    unsafe { inner_get::<u32, Rule>(table, &parsed_ipv4.source) }.copied()
+}
+
+#[inline(always)]
+fn lookup_bogon(source: u32) -> Option<BogonType> {
+    // Trie keys are matched byte by byte, so the address has to be in network order
+    let key = Key::new(32, source.to_be());
+    let bogon = unsafe { BOGONS.get(&key) }?;
+
+    if let Some(counter) = unsafe { BOGON_COUNTERS.get_mut(*bogon as u32) } {
+        *counter += 1;
+    }
+    Some(*bogon)
+}
+
+// Imported lists live in two tries, userspace fills the one lookups don't use
+// and then switches CONFIG_BLOCKLIST over to it
+#[inline(always)]
+fn lookup_blocklist(source: u32) -> Option<u32> {
+    let key = Key::new(32, source.to_be());
+    match config(CONFIG_BLOCKLIST) {
+        1 => unsafe { BLOCKLIST_0.get(&key) }.copied(),
+        2 => unsafe { BLOCKLIST_1.get(&key) }.copied(),
+        _ => None,
+    }
+}
+
+// What the source of a packet decides about it
+enum Source {
+    // Dropped or redirected, with the XDP return value
+    Handled(u32),
+    // Left to the checks that follow, with the PASS rule of the source if it has one
+    Allowed(Option<Rule>),
+}
+
+// Listed sources are dropped before any rule, as allow rules are installed for
+// every source seen. Blocked and redirected sources are handled whatever they
+// send, but an allowed source still has its ICMP filtered by type
+#[inline(always)]
+fn check_source(ctx: &XdpContext, parsed_ipv4: &IPV4) -> Source {
+    if let Some(list_id) = lookup_blocklist(parsed_ipv4.source) {
+        let log_entry = generate_log(*parsed_ipv4, XdpAction::DROP, Reason::BLOCKLIST, list_id);
+        emit(ctx, log_entry);
+        return Source::Handled(xdp_action::XDP_DROP);
+    }
+    let rule = match lookup_rule(parsed_ipv4) {
+        Some(rule) => rule,
+        None => return Source::Allowed(None),
+    };
+    if rule.action == XdpAction::PASS {
+        return Source::Allowed(Some(rule));
+    }
+    let log_entry = generate_log(*parsed_ipv4, rule.action, Reason::LISTED, rule.rule_id);
+    emit(ctx, log_entry);
+    match rule.action {
+        XdpAction::REDIRECT => Source::Handled(redirect(ctx, rule.port)),
+        _ => Source::Handled(rule.action as u32),
+    }
+}
+
+// Counts what an allowed source does for the detectors in userspace, which can't
+// rely on events as sources with a PASS rule send none
+#[inline(always)]
+fn track(parsed_ipv4: &IPV4, counts: u32) {
+    if counts == 0 {
+        return;
     }
-    let parsed_ipv4 = parse_ipv4(ctx)?;
+    let source = parsed_ipv4.source;
+    if counts & DetectCount::PACKETS.mask() != 0 {
+        match unsafe { DETECT_PACKETS.get_mut(&source) } {
+            Some(packets) => unsafe {
+                (*(packets as *mut u64 as *const AtomicU64)).fetch_add(1, Ordering::Relaxed);
+            },
+            None => {
+                let _ = unsafe { DETECT_PACKETS.insert(&source, &1, 0) };
+            }
+        }
+    }
+    let now = unsafe { bpf_ktime_get_ns() };
+    if counts & DetectCount::PORTS.mask() != 0 && parsed_ipv4.destination_port != 0 {
+        let key = ReachKey {
+            source,
+            target: parsed_ipv4.destination_port as u32,
+        };
+        reached(unsafe { &mut DETECT_PORTS }, &key, now);
+    }
+    if counts & DetectCount::HOSTS.mask() != 0 {
+        let key = ReachKey {
+            source,
+            target: parsed_ipv4.destination,
+        };
+        reached(unsafe { &mut DETECT_HOSTS }, &key, now);
+    }
+}
+
+#[inline(always)]
+fn reached(map: &mut LruHashMap<ReachKey, u64>, key: &ReachKey, now: u64) {
+    match unsafe { map.get_mut(key) } {
+        Some(seen) => *seen = now,
+        None => {
+            let _ = unsafe { map.insert(key, &now, 0) };
+        }
+    }
+}
 
-    if let Some(action) = unsafe { ACTION_LIST.get(&parsed_ipv4.source) } {
-        if let XdpAction::PASS = action {
+// Port knocking for TCP and UDP packets, once userspace set CONFIG_KNOCK_GATES.
+// Knocks are dropped whatever they do to the sequence, and the protected port of
+// a gate only passes while the gate is open for the source, which each packet to
+// it extends. Returns the reason and gate id of a packet to drop
+#[inline(always)]
+fn knock(parsed_ipv4: &IPV4, gates: u32) -> Option<(Reason, u32)> {
+    let port = parsed_ipv4.destination_port;
+    if gates == 0 || port == 0 {
+        return None;
+    }
+    let now = unsafe { bpf_ktime_get_ns() };
+    for index in 0..KNOCK_GATES {
+        if index >= gates {
+            break;
+        }
+        let gate = match unsafe { KNOCK_GATES_MAP.get(index) } {
+            Some(gate) => gate,
+            None => break,
+        };
+        let key = KnockKey {
+            source: parsed_ipv4.source,
+            gate: index,
+        };
+        let state = unsafe { KNOCK_STATE.get(&key) }.copied();
+        let gate_id = index + 1;
+
+        // Traffic to the open port keeps it open, so a session is only cut off
+        // after going quiet for gate.open
+        if parsed_ipv4.ip_proto == gate.protocol && port == gate.port {
+            return match unsafe { KNOCK_STATE.get_mut(&key) } {
+                Some(state) if state.step == gate.steps && now < state.deadline => {
+                    state.deadline = now + gate.open;
+                    None
+                }
+                _ => Some((Reason::KNOCK, gate_id)),
+            };
+        }
+        if parsed_ipv4.ip_proto != gate.knock_protocol {
+            continue;
+        }
+        let mut knock_port = false;
+        for step in 0..KNOCK_STEPS {
+            if step as u32 >= gate.steps {
+                break;
+            }
+            if gate.ports[step] == port {
+                knock_port = true;
+                break;
+            }
+        }
+        if !knock_port {
+            continue;
+        }
+
+        let (step, late) = match state {
+            Some(state) if now < state.deadline => (state.step, false),
+            Some(state) => (0, state.step < gate.steps),
+            None => (0, false),
+        };
+        // Knocking again doesn't close an open gate
+        if step >= gate.steps {
+            return Some((Reason::KNOCK, gate_id));
+        }
+        let expected = gate.ports.get(step as usize).copied();
+        let (step, mut reason) = if expected == Some(port) {
+            let step = step + 1;
+            if step == gate.steps {
+                (step, Reason::UNLOCKED)
+            } else {
+                (step, Reason::KNOCK)
+            }
+        } else if step > 0 {
+            // A wrong knock may still be the start of a new try
+            let restart = if gate.ports[0] == port { 1 } else { 0 };
+            (restart, Reason::MISKNOCK)
         } else {
-            let log_entry = generate_log(parsed_ipv4, *action);
-            unsafe { EVENTS.output(ctx, &log_entry, 0) };
+            (0, Reason::KNOCK)
         };
+        if late && reason == Reason::KNOCK {
+            reason = Reason::MISKNOCK;
+        }
+
+        if step == 0 {
+            let _ = unsafe { KNOCK_STATE.remove(&key) };
+        } else {
+            let wait = if step == gate.steps {
+                gate.open
+            } else {
+                gate.timeout
+            };
+            let state = KnockState {
+                step,
+                _pad: 0,
+                deadline: now + wait,
+            };
+            let _ = unsafe { KNOCK_STATE.insert(&key, &state, 0) };
+        }
+        return Some((reason, gate_id));
+    }
+    None
+}
+
+#[inline(always)]
+fn count_fragment(count: FragmentCount) {
+    if let Some(counter) = unsafe { FRAGMENT_COUNTERS.get_mut(count as u32) } {
+        *counter += 1;
+    }
+}
+
+// Records the byte range of a fragment and reports whether it overlaps one seen earlier.
+// Only the last FRAGMENT_RANGES fragments of a datagram are remembered.
+#[inline(always)]
+fn fragment_overlaps(parsed_ipv4: &IPV4, start: u32, end: u32) -> bool {
+    let key = FragmentKey {
+        source: parsed_ipv4.source,
+        destination: parsed_ipv4.destination,
+        id: parsed_ipv4.id,
+        protocol: parsed_ipv4.ip_proto,
+        _pad: 0,
+    };
 
-        return Ok(*action as u32);
+    let seen = match unsafe { FRAGMENTS.get_mut(&key) } {
+        Some(seen) => seen,
+        None => {
+            let mut ranges = FragmentRanges {
+                start: [0; FRAGMENT_RANGES],
+                end: [0; FRAGMENT_RANGES],
+                next: 1,
+            };
+            ranges.start[0] = start;
+            ranges.end[0] = end;
+            let _ = unsafe { FRAGMENTS.insert(&key, &ranges, 0) };
+            return false;
+        }
+    };
+
+    for i in 0..FRAGMENT_RANGES {
+        if (i as u32) < seen.next && start < seen.end[i] && seen.start[i] < end {
+            return true;
+        }
+    }
+    let slot = seen.next as usize % FRAGMENT_RANGES;
+    seen.start[slot] = start;
+    seen.end[slot] = end;
+    seen.next += 1;
+    false
+}
+
+// Applies the CONFIG_FRAGMENTS policies, returning the one that drops the packet
+#[inline(always)]
+fn check_fragment(parsed_ipv4: &IPV4, policies: u32) -> Option<FragmentPolicy> {
+    let offset = parsed_ipv4.frag_off & IP_OFFSET;
+    let more_fragments = parsed_ipv4.frag_off & IP_MF != 0;
+    if offset == 0 && !more_fragments {
+        return None;
     }
+    count_fragment(if offset == 0 {
+        FragmentCount::FIRST
+    } else {
+        FragmentCount::LATER
+    });
+
+    if policies & FragmentPolicy::DROP.mask() != 0 {
+        count_fragment(FragmentCount::DROPPED);
+        return Some(FragmentPolicy::DROP);
+    }
+
+    let payload_len = parsed_ipv4.total_len.saturating_sub(parsed_ipv4.header_len);
+
+    // RFC 1858: a first fragment too short to hold the transport header, or a TCP
+    // fragment at offset 1 that would rewrite the flags of the first one
+    if policies & FragmentPolicy::TINY.mask() != 0 {
+        let min_len = match parsed_ipv4.protocol {
+            PacketType::TCP => TCP_HDR_LEN,
+            PacketType::UDP => UDP_HDR_LEN,
+            PacketType::ICMP => ICMP_HDR_LEN,
+            PacketType::UNKNOW => 0,
+        };
+        let tiny = offset == 0 && payload_len < min_len;
+        let rewrites_flags = parsed_ipv4.protocol == PacketType::TCP && offset == 1;
+        if tiny || rewrites_flags {
+            count_fragment(FragmentCount::TINY);
+            return Some(FragmentPolicy::TINY);
+        }
+    }
+
+    if policies & FragmentPolicy::OVERLAP.mask() != 0 {
+        let start = offset as u32 * 8;
+        if fragment_overlaps(parsed_ipv4, start, start + payload_len as u32) {
+            count_fragment(FragmentCount::OVERLAP);
+            return Some(FragmentPolicy::OVERLAP);
+        }
+    }
+
+    None
+}
+
+// Counts the ICMP type and looks up its policy, an exact code match wins over a
+// policy for every code of the type
+#[inline(always)]
+fn lookup_icmp_policy(icmp: IcmpKey) -> Option<Rule> {
+    if let Some(counter) = unsafe { ICMP_COUNTERS.get_mut(icmp.icmp_type as u32) } {
+        *counter += 1;
+    }
+    if let Some(rule) = unsafe { ICMP_POLICY.get(&icmp) } {
+        return Some(*rule);
+    }
+    let any_code = IcmpKey {
+        icmp_type: icmp.icmp_type,
+        code: ICMP_ANY_CODE,
+    };
+    unsafe { ICMP_POLICY.get(&any_code) }.copied()
+}
+
+#[inline(always)]
+fn count_echo(count: EchoCount) {
+    if let Some(counter) = unsafe { ECHO_COUNTERS.get_mut(count as u32) } {
+        *counter += 1;
+    }
+}
+
+// Fixed one second windows shared by every cpu. The update isn't atomic, so the limit
+// can be overshot slightly under load
+#[inline(always)]
+fn echo_allowed(rate: u32) -> bool {
+    let window = match unsafe { ECHO_WINDOW.get_mut(0) } {
+        Some(window) => window,
+        None => return false,
+    };
+    let now = unsafe { bpf_ktime_get_ns() };
+    if now.wrapping_sub(window.start) >= NSEC_PER_SEC {
+        window.start = now;
+        window.replies = 0;
+    }
+    if window.replies >= rate {
+        return false;
+    }
+    window.replies += 1;
+    true
+}
+
+// Turns an echo request for one of the ECHO_ADDRESSES into its reply in place, so it can
+// go back out with XDP_TX. Returns None for packets the kernel stack should see
+#[inline(always)]
+fn answer_echo(
+    ctx: &XdpContext,
+    eth: &Ethernet,
+    parsed_ipv4: &IPV4,
+    rate: u32,
+) -> Result<Option<XdpAction>, ()> {
+    // The reply is built by rewriting headers, which only works for a plain unfragmented request
+    if rate == 0
+        || parsed_ipv4.tunnel != TunnelType::NONE
+        || parsed_ipv4.frag_off & (IP_MF | IP_OFFSET) != 0
+    {
+        return Ok(None);
+    }
+    match parsed_ipv4.icmp {
+        Some(icmp) if icmp.icmp_type == ICMP_ECHO_REQUEST && icmp.code == 0 => {}
+        _ => return Ok(None),
+    }
+    if unsafe { ECHO_ADDRESSES.get(&parsed_ipv4.destination) }.is_none() {
+        return Ok(None);
+    }
+    // Over the limit the request is dropped, so ping floods never reach the stack
+    if !echo_allowed(rate) {
+        count_echo(EchoCount::LIMITED);
+        return Ok(Some(XdpAction::DROP));
+    }
+
+    unsafe {
+        let eth_hdr: *mut ethhdr = ptr_at_mut(ctx, 0)?;
+        let dest = (*eth_hdr).h_dest;
+        (*eth_hdr).h_dest = (*eth_hdr).h_source;
+        (*eth_hdr).h_source = dest;
+
+        // Swapping the addresses leaves the header checksum as it is
+        let ip: *mut iphdr = ptr_at_mut(ctx, eth.l3_offset)?;
+        let source = (*ip).saddr;
+        (*ip).saddr = (*ip).daddr;
+        (*ip).daddr = source;
+        // TTL shares its checksum word with the protocol
+        let old = u16::from_be_bytes([(*ip).ttl, (*ip).protocol]);
+        let new = u16::from_be_bytes([ECHO_TTL, (*ip).protocol]);
+        (*ip).ttl = ECHO_TTL;
+        (*ip).check = csum_replace(u16::from_be((*ip).check), old, new).to_be();
+
+        let icmp: *mut icmphdr = ptr_at_mut(ctx, parsed_ipv4.l4_offset)?;
+        let old = u16::from_be_bytes([ICMP_ECHO_REQUEST, 0]);
+        let new = u16::from_be_bytes([ICMP_ECHO_REPLY, 0]);
+        (*icmp).type_ = ICMP_ECHO_REPLY;
+        (*icmp).checksum = csum_replace(u16::from_be((*icmp).checksum), old, new).to_be();
+    }
+    count_echo(EchoCount::REPLIED);
+    Ok(Some(XdpAction::TX))
+}
+
+// Sends the packet out of a REDIRECT_PORTS interface, or up to userspace through
+// the AF_XDP socket of its receive queue
+#[inline(always)]
+fn redirect(ctx: &XdpContext, port: u32) -> u32 {
+    if port == XSK_PORT {
+        let queue = unsafe { (*ctx.ctx).rx_queue_index };
+        // Queues without a socket leave the packet to the kernel stack
+        return unsafe { XSK_SOCKETS.redirect(queue, xdp_action::XDP_PASS as u64) };
+    }
+    unsafe { REDIRECT_PORTS_MAP.redirect(port, 0) }
+}
+
+#[inline(always)]
+fn csum_replace4(check: u16, old: u32, new: u32) -> u16 {
+    let check = csum_replace(check, (old >> 16) as u16, (new >> 16) as u16);
+    csum_replace(check, old as u16, new as u16)
+}
+
+// Port forwards take precedence over 1:1 NAT of the whole address
+#[inline(always)]
+fn lookup_nat(
+    map: &mut HashMap<NatKey, NatTarget>,
+    address: u32,
+    port: Option<u16>,
+    protocol: u8,
+) -> Option<NatTarget> {
+    if let Some(port) = port {
+        let key = NatKey {
+            address,
+            port,
+            protocol,
+            _pad: 0,
+        };
+        if let Some(target) = unsafe { map.get(&key) } {
+            return Some(*target);
+        }
+    }
+    let key = NatKey {
+        address,
+        port: 0,
+        protocol: 0,
+        _pad: 0,
+    };
+    unsafe { map.get(&key) }.copied()
+}
+
+// Translates the destination of packets for a DNAT entry before the stack routes them,
+// updating the IP and TCP/UDP checksums incrementally
+#[inline(always)]
+fn nat_ingress(ctx: &XdpContext, eth: &Ethernet, parsed_ipv4: &mut IPV4) -> Result<(), ()> {
+    if parsed_ipv4.tunnel != TunnelType::NONE {
+        return Ok(());
+    }
+    // Only the first fragment has the ports and the L4 checksum
+    let l4_checksum = if parsed_ipv4.frag_off & IP_OFFSET == 0 {
+        l4_checksum_offset(parsed_ipv4.ip_proto)
+    } else {
+        None
+    };
+    // A header too short to hold the port is left to the checks rather than
+    // aborting the packet
+    let dest_port = match l4_checksum {
+        Some(_) => match unsafe { ptr_at::<u16>(ctx, parsed_ipv4.l4_offset + 2) } {
+            Ok(port) => Some(u16::from_be(unsafe { *port })),
+            Err(()) => return Ok(()),
+        },
+        None => None,
+    };
+    let target = match lookup_nat(
+        unsafe { &mut DNAT },
+        parsed_ipv4.destination,
+        dest_port,
+        parsed_ipv4.ip_proto,
+    ) {
+        Some(target) => target,
+        None => return Ok(()),
+    };
 
-    let log_entry = generate_log(parsed_ipv4, XdpAction::PASS);
     unsafe {
-        EVENTS.output(ctx, &log_entry, 0);
+        let ip: *mut iphdr = ptr_at_mut(ctx, eth.l3_offset)?;
+        (*ip).daddr = target.address.to_be();
+        (*ip).check = csum_replace4(
+            u16::from_be((*ip).check),
+            parsed_ipv4.destination,
+            target.address,
+        )
+        .to_be();
+
+        if let (Some(offset), Some(old_port)) = (l4_checksum, dest_port) {
+            let new_port = if target.port != 0 {
+                target.port
+            } else {
+                old_port
+            };
+            let port: *mut u16 = ptr_at_mut(ctx, parsed_ipv4.l4_offset + 2)?;
+            *port = new_port.to_be();
+            parsed_ipv4.destination_port = new_port;
+
+            // A zero UDP checksum means the sender didn't compute one
+            let check: *mut u16 = ptr_at_mut(ctx, parsed_ipv4.l4_offset + offset)?;
+            if parsed_ipv4.ip_proto != IPPROTO_UDP || *check != 0 {
+                // The pseudo header covers the destination address
+                let sum = csum_replace4(
+                    u16::from_be(*check),
+                    parsed_ipv4.destination,
+                    target.address,
+                );
+                let sum = csum_replace(sum, old_port, new_port);
+                *check = if sum == 0 && parsed_ipv4.ip_proto == IPPROTO_UDP {
+                    0xffff
+                } else {
+                    sum.to_be()
+                };
+            }
+        }
+    }
+    parsed_ipv4.destination = target.address;
+    Ok(())
+}
+
+// Rewrites the source of packets leaving through the interface for an SNAT entry, the
+// reverse of the DNAT entry. Runs as a TC egress classifier, as XDP only sees ingress
+#[inline(always)]
+fn try_nat_egress(ctx: &mut SkBuffContext) -> Result<i32, i64> {
+    let h_proto = u16::from_be(ctx.load(offset_of!(ethhdr, h_proto))?);
+    if h_proto != ETH_P_IP {
+        return Ok(TC_ACT_OK);
+    }
+    let l3_offset = ETH_HDR_LEN;
+    let version_ihl: u8 = ctx.load(l3_offset)?;
+    let l4_offset = l3_offset + (version_ihl & 0x0f) as usize * 4;
+    let ip_proto: u8 = ctx.load(l3_offset + offset_of!(iphdr, protocol))?;
+    let frag_off = u16::from_be(ctx.load(l3_offset + offset_of!(iphdr, frag_off))?);
+    let source = u32::from_be(ctx.load(l3_offset + offset_of!(iphdr, saddr))?);
+
+    let l4_checksum = if frag_off & IP_OFFSET == 0 {
+        l4_checksum_offset(ip_proto)
+    } else {
+        None
+    };
+    let source_port = match l4_checksum {
+        Some(_) => Some(u16::from_be(ctx.load(l4_offset)?)),
+        None => None,
+    };
+    let target = match lookup_nat(unsafe { &mut SNAT }, source, source_port, ip_proto) {
+        Some(target) => target,
+        None => return Ok(TC_ACT_OK),
+    };
+
+    if let (Some(offset), Some(old_port)) = (l4_checksum, source_port) {
+        let new_port = if target.port != 0 {
+            target.port
+        } else {
+            old_port
+        };
+        // MARK_MANGLED_0 leaves a zero UDP checksum alone and never produces one
+        let mangled = if ip_proto == IPPROTO_UDP {
+            BPF_F_MARK_MANGLED_0
+        } else {
+            0
+        };
+        let check = l4_offset + offset;
+        ctx.l4_csum_replace(
+            check,
+            source.to_be() as u64,
+            target.address.to_be() as u64,
+            BPF_F_PSEUDO_HDR | mangled | 4,
+        )?;
+        ctx.l4_csum_replace(
+            check,
+            old_port.to_be() as u64,
+            new_port.to_be() as u64,
+            mangled | 2,
+        )?;
+        ctx.store(l4_offset, &new_port.to_be(), 0)?;
+    }
+    ctx.l3_csum_replace(
+        l3_offset + offset_of!(iphdr, check),
+        source.to_be() as u64,
+        target.address.to_be() as u64,
+        4,
+    )?;
+    ctx.store(
+        l3_offset + offset_of!(iphdr, saddr),
+        &target.address.to_be(),
+        0,
+    )?;
+    Ok(TC_ACT_OK)
+}
+
+// Standard internet checksum of an IPv4 header without options
+#[inline(always)]
+fn ip_checksum(hdr: *const u16) -> u16 {
+    let mut sum = 0u32;
+    for i in 0..IP_HDR_LEN / 2 {
+        sum += unsafe { *hdr.add(i) } as u32;
     }
+    sum = (sum & 0xffff) + (sum >> 16);
+    sum = (sum & 0xffff) + (sum >> 16);
+    !(sum as u16)
+}
+
+// Mixes the 5-tuple so every packet of a flow picks the same MAGLEV row
+#[inline(always)]
+fn flow_hash(parsed_ipv4: &IPV4, source_port: u16, dest_port: u16) -> u32 {
+    let mut hash = parsed_ipv4.source;
+    for word in [
+        parsed_ipv4.destination,
+        (source_port as u32) << 16 | dest_port as u32,
+        parsed_ipv4.ip_proto as u32,
+    ] {
+        hash ^= word;
+        // murmur3 finalizer
+        hash ^= hash >> 16;
+        hash = hash.wrapping_mul(0x85eb_ca6b);
+        hash ^= hash >> 13;
+        hash = hash.wrapping_mul(0xc2b2_ae35);
+        hash ^= hash >> 16;
+    }
+    hash
+}
+
+// Wraps the packet in an outer IPv4 header addressed to the backend. Only untagged
+// frames are encapsulated, the ethernet header is rebuilt in front of the new one
+#[inline(always)]
+fn encapsulate(ctx: &XdpContext, backend: &Backend) -> Result<(), ()> {
+    if unsafe { bpf_xdp_adjust_head(ctx.ctx, -(IP_HDR_LEN as i32)) } != 0 {
+        return Err(());
+    }
+    unsafe {
+        let eth_hdr: *mut ethhdr = ptr_at_mut(ctx, 0)?;
+        let outer: *mut iphdr = ptr_at_mut(ctx, ETH_HDR_LEN)?;
+        let inner: *const iphdr = ptr_at(ctx, ETH_HDR_LEN + IP_HDR_LEN)?;
+
+        (*eth_hdr).h_dest = backend.mac;
+        (*eth_hdr).h_source = backend.source_mac;
+        (*eth_hdr).h_proto = ETH_P_IP.to_be();
+
+        let inner_len = u16::from_be((*inner).tot_len);
+        (*outer).set_version(4);
+        (*outer).set_ihl((IP_HDR_LEN / 4) as u8);
+        (*outer).tos = (*inner).tos;
+        (*outer).tot_len = (inner_len + IP_HDR_LEN as u16).to_be();
+        (*outer).id = 0;
+        (*outer).frag_off = 0;
+        (*outer).ttl = IPIP_TTL;
+        (*outer).protocol = IPPROTO_IPIP;
+        (*outer).check = 0;
+        (*outer).saddr = config(CONFIG_LB_SOURCE).to_be();
+        (*outer).daddr = backend.address.to_be();
+        (*outer).check = ip_checksum(outer as *const u16);
+    }
+    Ok(())
+}
+
+// Sends TCP and UDP packets for a SERVICES VIP to one of its backends, picked by Maglev
+// hashing of the 5-tuple so no per-flow state is needed. Returns the XDP action and
+// the service id, or None for packets that aren't for a service
+#[inline(always)]
+fn balance(
+    ctx: &XdpContext,
+    eth: &Ethernet,
+    parsed_ipv4: &IPV4,
+) -> Result<Option<(XdpAction, u32, u32)>, ()> {
+    // Later fragments have no ports to hash, so fragmented traffic isn't balanced
+    if parsed_ipv4.tunnel != TunnelType::NONE
+        || parsed_ipv4.frag_off & (IP_MF | IP_OFFSET) != 0
+        || (parsed_ipv4.protocol != PacketType::TCP && parsed_ipv4.protocol != PacketType::UDP)
+    {
+        return Ok(None);
+    }
+    // TCP and UDP both start with the source and destination ports, a header too
+    // short to hold them isn't balanced
+    let (source_port, dest_port) = match unsafe { ptr_at::<[u16; 2]>(ctx, parsed_ipv4.l4_offset) } {
+        Ok(ports) => unsafe { (u16::from_be((*ports)[0]), u16::from_be((*ports)[1])) },
+        Err(()) => return Ok(None),
+    };
+    let key = ServiceKey {
+        vip: parsed_ipv4.destination,
+        port: dest_port,
+        protocol: parsed_ipv4.ip_proto,
+        _pad: 0,
+    };
+    let service = match unsafe { SERVICES.get(&key) } {
+        Some(service) => *service,
+        None => return Ok(None),
+    };
+
+    let row =
+        service.id * MAGLEV_SIZE + flow_hash(parsed_ipv4, source_port, dest_port) % MAGLEV_SIZE;
+    let slot = match unsafe { MAGLEV.get(row) } {
+        Some(slot) => *slot,
+        None => return Ok(None),
+    };
+    let backend = match unsafe { BACKENDS.get(service.id * LB_BACKENDS + slot) } {
+        Some(backend) => *backend,
+        None => return Ok(None),
+    };
+
+    match service.forward {
+        ForwardMode::MAC => unsafe {
+            let eth_hdr: *mut ethhdr = ptr_at_mut(ctx, 0)?;
+            (*eth_hdr).h_dest = backend.mac;
+            (*eth_hdr).h_source = backend.source_mac;
+        },
+        ForwardMode::IPIP => {
+            if eth.l3_offset != ETH_HDR_LEN {
+                return Ok(None);
+            }
+            encapsulate(ctx, &backend)?;
+        }
+    }
+
+    if backend.port == BACKEND_TX {
+        return Ok(Some((XdpAction::TX, service.id, xdp_action::XDP_TX)));
+    }
+    let action = unsafe { REDIRECT_PORTS_MAP.redirect(backend.port, 0) };
+    Ok(Some((XdpAction::REDIRECT, service.id, action)))
+}
+
+fn try_xdp_firewall(ctx: &XdpContext) -> Result<u32, ()> {
+    let eth = match parse_ethernet(ctx)? {
+        Some(eth) => eth,
+        None => return Ok(xdp_action::XDP_PASS),
+    };
+
+    if let Some(check) = sanity_check(ctx, &eth, config(CONFIG_CHECKS))? {
+        // A truncated header may not even hold the addresses
+        let parsed_ipv4 = parse_ipv4(ctx, &eth).unwrap_or_else(|_| IPV4::unparsed(&eth));
+        let log_entry = generate_log(
+            parsed_ipv4,
+            XdpAction::DROP,
+            Reason::MALFORMED,
+            check as u32,
+        );
+        emit(ctx, log_entry);
+        return Ok(xdp_action::XDP_DROP);
+    }
+    let parsed_ipv4 = parse_ipv4(ctx, &eth)?;
+
+    // Spoofed sources are dropped before the action list is consulted.
+    // The trie is empty unless bogon filtering was enabled from userspace.
+    // The bogon list that matched doubles as the rule id.
+    if let Some(bogon) = lookup_bogon(parsed_ipv4.source) {
+        let log_entry = generate_log(parsed_ipv4, XdpAction::DROP, Reason::BOGON, bogon as u32);
+        emit(ctx, log_entry);
+        return Ok(xdp_action::XDP_DROP);
+    }
+
+    // Non-first fragments carry no transport header, so they're judged before anything
+    // looks at one
+    if let Some(policy) = check_fragment(&parsed_ipv4, config(CONFIG_FRAGMENTS)) {
+        let log_entry = generate_log(
+            parsed_ipv4,
+            XdpAction::DROP,
+            Reason::FRAGMENT,
+            policy as u32,
+        );
+        emit(ctx, log_entry);
+        return Ok(xdp_action::XDP_DROP);
+    }
+
+    // The outer source is checked before looking through a tunnel, so a blocked host
+    // can't get past its rule by wrapping its traffic
+    let mut rule = match check_source(ctx, &parsed_ipv4) {
+        Source::Handled(ret) => return Ok(ret),
+        Source::Allowed(rule) => rule,
+    };
+
+    // Rules then apply to the inner header, falling back to the outer one when the
+    // inner header can't be parsed
+    let mut parsed_ipv4 = match find_inner(ctx, &eth, &parsed_ipv4, config(CONFIG_TUNNELS)) {
+        Some((inner_eth, tunnel)) => {
+            let inner = decapsulate(ctx, &inner_eth, tunnel, &parsed_ipv4);
+            if let Some(check) = sanity_check(ctx, &inner_eth, config(CONFIG_CHECKS))? {
+                let log_entry = generate_log(
+                    inner.unwrap_or(parsed_ipv4),
+                    XdpAction::DROP,
+                    Reason::MALFORMED,
+                    check as u32,
+                );
+                emit(ctx, log_entry);
+                return Ok(xdp_action::XDP_DROP);
+            }
+            match inner {
+                Some(inner) => {
+                    rule = match check_source(ctx, &inner) {
+                        Source::Handled(ret) => return Ok(ret),
+                        Source::Allowed(rule) => rule,
+                    };
+                    inner
+                }
+                None => parsed_ipv4,
+            }
+        }
+        None => parsed_ipv4,
+    };
+
+    track(&parsed_ipv4, config(CONFIG_DETECT));
+
+    if let Some((reason, gate_id)) = knock(&parsed_ipv4, config(CONFIG_KNOCK_GATES)) {
+        let log_entry = generate_log(parsed_ipv4, XdpAction::DROP, reason, gate_id);
+        emit(ctx, log_entry);
+        return Ok(xdp_action::XDP_DROP);
+    }
+
+    // Allowed traffic to a NAT address continues with the translated destination
+    nat_ingress(ctx, &eth, &mut parsed_ipv4)?;
+
+    if let Some((action, service_id, ret)) = balance(ctx, &eth, &parsed_ipv4)? {
+        let log_entry = generate_log(parsed_ipv4, action, Reason::SERVICE, service_id);
+        emit(ctx, log_entry);
+        return Ok(ret);
+    }
+
+    let icmp_rule = parsed_ipv4.icmp.and_then(lookup_icmp_policy);
+    if let Some(icmp_rule) = icmp_rule {
+        if let XdpAction::PASS = icmp_rule.action {
+        } else {
+            let log_entry = generate_log(
+                parsed_ipv4,
+                icmp_rule.action,
+                Reason::ICMP,
+                icmp_rule.rule_id,
+            );
+            emit(ctx, log_entry);
+            return Ok(icmp_rule.action as u32);
+        }
+    }
+
+    // Pings the policy lets through are answered here instead of by the stack
+    if let Some(action) = answer_echo(ctx, &eth, &parsed_ipv4, config(CONFIG_ECHO_RATE))? {
+        let log_entry = generate_log(parsed_ipv4, action, Reason::ECHO, 0);
+        emit(ctx, log_entry);
+        return Ok(action as u32);
+    }
+
+    if let Some(icmp_rule) = icmp_rule {
+        let log_entry = generate_log(
+            parsed_ipv4,
+            icmp_rule.action,
+            Reason::ICMP,
+            icmp_rule.rule_id,
+        );
+        emit(ctx, log_entry);
+        return Ok(icmp_rule.action as u32);
+    }
+
+    // Sources with a PASS rule send no events, but their traffic is still counted
+    if let Some(rule) = rule {
+        let log_entry = generate_log(parsed_ipv4, rule.action, Reason::LISTED, rule.rule_id);
+        count(ctx, &log_entry);
+        return Ok(rule.action as u32);
+    }
+
+    let log_entry = generate_log(parsed_ipv4, XdpAction::PASS, Reason::NONE, 0);
+    emit(ctx, log_entry);
 
     Ok(xdp_action::XDP_PASS)
 }
@@ -109,15 +1423,124 @@ fn try_xdp_firewall(ctx: &XdpContext) -> Result<u32, ()> {
 #[map(name = "EVENTS")]
 static mut EVENTS: PerfEventArray<PacketLog> = PerfEventArray::with_max_entries(1034, 0);
 
-#[map(name = "ACTION_LIST")]
-static mut ACTION_LIST: HashMap<u32, XdpAction> = HashMap::with_max_entries(1024, 0);
+#[map(name = "SFLOW_SAMPLES")]
+static mut SFLOW_SAMPLES: PerfEventArray<SflowSample> = PerfEventArray::with_max_entries(1024, 0);
+
+#[map(name = "ACTION_COUNTERS")]
+static mut ACTION_COUNTERS: PerCpuArray<u64> =
+    PerCpuArray::with_max_entries(ACTION_COUNTER_SLOTS, 0);
+
+#[map(name = "RULE_TABLES")]
+static mut RULE_TABLES: ArrayOfMaps = ArrayOfMaps::pinned(1, 0);
+
+#[map(name = "VLAN_ACTION_LIST")]
+static mut VLAN_ACTION_LIST: HashMap<VlanKey, Rule> = HashMap::with_max_entries(1024, 0);
+
+#[map(name = "ICMP_POLICY")]
+static mut ICMP_POLICY: HashMap<IcmpKey, Rule> = HashMap::with_max_entries(256, 0);
+
+#[map(name = "ICMP_COUNTERS")]
+static mut ICMP_COUNTERS: PerCpuArray<u64> = PerCpuArray::with_max_entries(ICMP_TYPES, 0);
+
+#[map(name = "ECHO_ADDRESSES")]
+static mut ECHO_ADDRESSES: HashMap<u32, u8> = HashMap::with_max_entries(64, 0);
+
+#[map(name = "ECHO_WINDOW")]
+static mut ECHO_WINDOW: Array<EchoWindow> = Array::with_max_entries(1, 0);
+
+#[map(name = "ECHO_COUNTERS")]
+static mut ECHO_COUNTERS: PerCpuArray<u64> = PerCpuArray::with_max_entries(ECHO_COUNTS, 0);
+
+#[map(name = "REDIRECT_PORTS")]
+static mut REDIRECT_PORTS_MAP: DevMap = DevMap::with_max_entries(REDIRECT_PORTS, 0);
+
+#[map(name = "XSK_SOCKETS")]
+static mut XSK_SOCKETS: XskMap = XskMap::with_max_entries(XSK_QUEUES, 0);
+
+#[map(name = "SERVICES")]
+static mut SERVICES: HashMap<ServiceKey, Service> = HashMap::with_max_entries(LB_SERVICES, 0);
+
+#[map(name = "MAGLEV")]
+static mut MAGLEV: Array<u32> = Array::with_max_entries(LB_SERVICES * MAGLEV_SIZE, 0);
+
+#[map(name = "BACKENDS")]
+static mut BACKENDS: Array<Backend> = Array::with_max_entries(LB_SERVICES * LB_BACKENDS, 0);
+
+#[map(name = "DNAT")]
+static mut DNAT: HashMap<NatKey, NatTarget> = HashMap::with_max_entries(NAT_ENTRIES, 0);
+
+#[map(name = "SNAT")]
+static mut SNAT: HashMap<NatKey, NatTarget> = HashMap::with_max_entries(NAT_ENTRIES, 0);
+
+#[map(name = "AGGREGATES")]
+static mut AGGREGATES: LruHashMap<AggregateKey, AggregateValue> =
+    LruHashMap::with_max_entries(AGGREGATE_ENTRIES, 0);
+
+#[map(name = "CONFIG")]
+static mut CONFIG: Array<u32> = Array::with_max_entries(CONFIG_SIZE, 0);
+
+#[map(name = "BOGONS")]
+static mut BOGONS: LpmTrie<u32, BogonType> = LpmTrie::with_max_entries(64, BPF_F_NO_PREALLOC);
+
+#[map(name = "BLOCKLIST_0")]
+static mut BLOCKLIST_0: LpmTrie<u32, u32> =
+    LpmTrie::with_max_entries(BLOCKLIST_ENTRIES, BPF_F_NO_PREALLOC);
+
+#[map(name = "BLOCKLIST_1")]
+static mut BLOCKLIST_1: LpmTrie<u32, u32> =
+    LpmTrie::with_max_entries(BLOCKLIST_ENTRIES, BPF_F_NO_PREALLOC);
+
+#[map(name = "BOGON_COUNTERS")]
+static mut BOGON_COUNTERS: PerCpuArray<u64> = PerCpuArray::with_max_entries(BOGON_TYPES, 0);
+
+#[map(name = "FRAGMENT_COUNTERS")]
+static mut FRAGMENT_COUNTERS: PerCpuArray<u64> = PerCpuArray::with_max_entries(FRAGMENT_COUNTS, 0);
+
+#[map(name = "FRAGMENTS")]
+static mut FRAGMENTS: LruHashMap<FragmentKey, FragmentRanges> =
+    LruHashMap::with_max_entries(4096, 0);
+
+#[map(name = "DETECT_PACKETS")]
+static mut DETECT_PACKETS: LruHashMap<u32, u64> = LruHashMap::with_max_entries(DETECT_SOURCES, 0);
+
+#[map(name = "DETECT_PORTS")]
+static mut DETECT_PORTS: LruHashMap<ReachKey, u64> =
+    LruHashMap::with_max_entries(DETECT_TARGETS, 0);
+
+#[map(name = "DETECT_HOSTS")]
+static mut DETECT_HOSTS: LruHashMap<ReachKey, u64> =
+    LruHashMap::with_max_entries(DETECT_TARGETS, 0);
+
+#[map(name = "KNOCK_GATES")]
+static mut KNOCK_GATES_MAP: Array<KnockGate> = Array::with_max_entries(KNOCK_GATES, 0);
+
+#[map(name = "KNOCK_STATE")]
+static mut KNOCK_STATE: LruHashMap<KnockKey, KnockState> =
+    LruHashMap::with_max_entries(KNOCK_SOURCES, 0);
 
 #[xdp(name = "ebpfapp")]
 pub fn ebpfapp(ctx: XdpContext) -> u32 {
-    match { try_xdp_firewall(&ctx) } {
+    // sFlow sees every frame, whatever the firewall does with it or whether it sends
+    // an event. Whether it is sampled and its length are settled before the frame
+    // can be rewritten
+    let sflow_rate = config(CONFIG_SFLOW_RATE);
+    let len = (ctx.data_end() - ctx.data()) as u32;
+    let sampled =
+        sflow_rate != 0 && (sflow_rate == 1 || unsafe { bpf_get_prandom_u32() } % sflow_rate == 0);
+    let ret = match { try_xdp_firewall(&ctx) } {
         Ok(ret) => ret,
         Err(_) => xdp_action::XDP_ABORTED,
+    };
+    if sflow_rate != 0 {
+        sflow(&ctx, ret, len, sampled, sflow_rate);
     }
+    ret
+}
+
+#[classifier(name = "ebpfapp_egress")]
+pub fn ebpfapp_egress(mut ctx: SkBuffContext) -> i32 {
+    // A packet that can't be parsed leaves untouched
+    try_nat_egress(&mut ctx).unwrap_or(TC_ACT_OK)
 }
 
 #[panic_handler]
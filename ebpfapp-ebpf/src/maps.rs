@@ -0,0 +1,120 @@
+use aya_bpf::{
+    bindings::{
+        bpf_map_def,
+        bpf_map_type::{BPF_MAP_TYPE_ARRAY_OF_MAPS, BPF_MAP_TYPE_DEVMAP, BPF_MAP_TYPE_XSKMAP},
+    },
+    cty::c_void,
+    helpers::{bpf_map_lookup_elem, bpf_redirect_map},
+};
+
+// The loader reuses the map pinned under its name instead of creating one
+const PIN_BY_NAME: u32 = 1;
+
+// aya-bpf has no devmap yet, so the definition is spelled out here. Keys are ports,
+// values the ifindex of the interface packets are redirected to
+#[repr(transparent)]
+pub struct DevMap {
+    def: bpf_map_def,
+}
+
+impl DevMap {
+    pub const fn with_max_entries(max_entries: u32, flags: u32) -> DevMap {
+        DevMap {
+            def: bpf_map_def {
+                type_: BPF_MAP_TYPE_DEVMAP,
+                key_size: 4,
+                value_size: 4,
+                max_entries,
+                map_flags: flags,
+                id: 0,
+                pinning: 0,
+            },
+        }
+    }
+
+    // XDP action that sends the packet out of the port's interface
+    #[inline(always)]
+    pub fn redirect(&mut self, port: u32, flags: u64) -> u32 {
+        unsafe { bpf_redirect_map(&mut self.def as *mut _ as *mut c_void, port, flags) as u32 }
+    }
+}
+
+// AF_XDP sockets by receive queue, values are socket fds written from userspace
+#[repr(transparent)]
+pub struct XskMap {
+    def: bpf_map_def,
+}
+
+impl XskMap {
+    pub const fn with_max_entries(max_entries: u32, flags: u32) -> XskMap {
+        XskMap {
+            def: bpf_map_def {
+                type_: BPF_MAP_TYPE_XSKMAP,
+                key_size: 4,
+                value_size: 4,
+                max_entries,
+                map_flags: flags,
+                id: 0,
+                pinning: 0,
+            },
+        }
+    }
+
+    // XDP action that delivers the packet to the socket of the queue. The low bits of
+    // flags are the action taken when the queue has no socket
+    #[inline(always)]
+    pub fn redirect(&mut self, queue: u32, flags: u64) -> u32 {
+        unsafe { bpf_redirect_map(&mut self.def as *mut _ as *mut c_void, queue, flags) as u32 }
+    }
+}
+
+// Maps indexed by position, neither aya-bpf nor its loader support them yet. Creating
+// one needs a template of the inner maps, so userspace creates and pins the map
+// before loading and this definition only has to match it
+#[repr(transparent)]
+pub struct ArrayOfMaps {
+    def: bpf_map_def,
+}
+
+impl ArrayOfMaps {
+    pub const fn pinned(max_entries: u32, flags: u32) -> ArrayOfMaps {
+        ArrayOfMaps {
+            def: bpf_map_def {
+                type_: BPF_MAP_TYPE_ARRAY_OF_MAPS,
+                key_size: 4,
+                value_size: 4,
+                max_entries,
+                map_flags: flags,
+                id: 0,
+                pinning: PIN_BY_NAME,
+            },
+        }
+    }
+
+    // The inner map at index, for inner_get
+    #[inline(always)]
+    pub fn get(&mut self, index: u32) -> Option<*mut c_void> {
+        let map = unsafe {
+            bpf_map_lookup_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                &index as *const _ as *const c_void,
+            )
+        };
+        if map.is_null() {
+            None
+        } else {
+            Some(map)
+        }
+    }
+}
+
+// Looks key up in an inner map of an ArrayOfMaps
+#[inline(always)]
+pub unsafe fn inner_get<'a, K, V>(map: *mut c_void, key: &K) -> Option<&'a V> {
+    let value = bpf_map_lookup_elem(map, key as *const _ as *const c_void);
+    if value.is_null() {
+        None
+    } else {
+        Some(&*(value as *const V))
+    }
+}
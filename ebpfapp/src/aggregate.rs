@@ -0,0 +1,130 @@
+use aya::maps::{Array, HashMap, MapRef};
+use aya::Bpf;
+use ebpfapp_common::{AggregateKey, AggregateValue, CONFIG_AGGREGATE};
+use std::cmp::Reverse;
+use std::collections::HashMap as StdHashMap;
+use std::convert::TryFrom;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use crate::parser::ParserToString;
+
+// Flow and action an aggregate counts, in a form that can key a std map
+pub type Flow = (u32, u32, u16, u16, u8, u32);
+
+fn flow(key: &AggregateKey) -> Flow {
+    (
+        key.source,
+        key.destination,
+        key.source_port,
+        key.destination_port,
+        key.packet_type as u8,
+        key.action as u32,
+    )
+}
+
+// A flow's traffic since the last flush
+pub struct Summary {
+    pub key: AggregateKey,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+// Reads the AGGREGATES map the XDP program counts packets in. Entries are never
+// deleted from userspace, as that would race with the counting, so each flush
+// reports how much the counters grew since the previous one
+pub struct Aggregates {
+    map: HashMap<MapRef, AggregateKey, AggregateValue>,
+    seen: StdHashMap<Flow, AggregateValue>,
+}
+
+impl Aggregates {
+    pub fn new(bpf: &Bpf) -> Result<Self, anyhow::Error> {
+        let mut config: Array<_, u32> = Array::try_from(bpf.map_mut("CONFIG")?)?;
+        config.set(CONFIG_AGGREGATE, 1, 0)?;
+        Ok(Aggregates {
+            map: HashMap::try_from(bpf.map("AGGREGATES")?)?,
+            seen: StdHashMap::new(),
+        })
+    }
+
+    pub fn flush(&mut self) -> Vec<Summary> {
+        growth(&mut self.seen, self.map.iter().flatten())
+    }
+}
+
+// What each entry counted since it was last seen, which becomes the new last seen
+pub fn growth(
+    seen: &mut StdHashMap<Flow, AggregateValue>,
+    entries: impl Iterator<Item = (AggregateKey, AggregateValue)>,
+) -> Vec<Summary> {
+    let mut summaries = Vec::new();
+    let mut now = StdHashMap::new();
+    for (key, value) in entries {
+        let flow = flow(&key);
+        // An entry the LRU evicted and the program added again starts from zero
+        let (packets, bytes) = match seen.get(&flow) {
+            Some(last) if last.packets <= value.packets => {
+                (value.packets - last.packets, value.bytes - last.bytes)
+            }
+            _ => (value.packets, value.bytes),
+        };
+        if packets > 0 {
+            summaries.push(Summary {
+                key,
+                packets,
+                bytes,
+            });
+        }
+        now.insert(flow, value);
+    }
+    *seen = now;
+    summaries
+}
+
+// Adds up the flows between the same addresses, whatever their ports, so a
+// port scan makes one line rather than one per port
+fn fold_ports(summaries: Vec<Summary>) -> Vec<Summary> {
+    let mut folded: StdHashMap<Flow, Summary> = StdHashMap::new();
+    for summary in summaries {
+        let key = AggregateKey {
+            source_port: 0,
+            destination_port: 0,
+            ..summary.key
+        };
+        let total = folded.entry(flow(&key)).or_insert(Summary {
+            key,
+            packets: 0,
+            bytes: 0,
+        });
+        total.packets += summary.packets;
+        total.bytes += summary.bytes;
+    }
+    folded.into_values().collect()
+}
+
+// One line per source, destination, protocol and action that saw traffic in the
+// interval, busiest first. The flow records keep the ports
+pub fn log_aggregates(mut aggregates: Aggregates, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let mut summaries = fold_ports(aggregates.flush());
+            summaries.sort_by_key(|summary| Reverse(summary.packets));
+            for summary in summaries {
+                println!(
+                    "{} → {} {} {} ×{} ({} bytes) in {}s",
+                    Ipv4Addr::from(summary.key.source),
+                    Ipv4Addr::from(summary.key.destination),
+                    summary.key.packet_type.to_str(),
+                    summary.key.action.to_str(),
+                    summary.packets,
+                    summary.bytes,
+                    interval.as_secs()
+                );
+            }
+        }
+    });
+}
@@ -0,0 +1,463 @@
+use anyhow::bail;
+use aya::maps::lpm_trie::{Key, LpmTrie};
+use aya::maps::{Array, MapRefMut};
+use aya::Bpf;
+use ebpfapp_common::{BLOCKLIST_ENTRIES, CONFIG_BLOCKLIST};
+use log::{info, warn};
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use tokio::task;
+
+use crate::geoip::{country_prefixes, GeoipConfig};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListFormat {
+    // One address or CIDR prefix per line, # starts a comment
+    List,
+    // Spamhaus DROP and EDROP: "prefix ; SBL id", ; starts a comment
+    Spamhaus,
+    // Output of ipset save, the add lines of hash:ip and hash:net sets
+    Ipset,
+}
+
+fn default_format() -> ListFormat {
+    ListFormat::List
+}
+
+// A list of sources to drop, e.g.
+//
+// [[blocklist]]
+// name = "spamhaus-drop"
+// path = "/var/lib/ebpfapp/drop.txt"
+// format = "spamhaus"
+#[derive(Debug, Clone, Deserialize)]
+pub struct BlocklistConfig {
+    pub name: String,
+    pub path: PathBuf,
+    #[serde(default = "default_format")]
+    pub format: ListFormat,
+}
+
+// Network address in host order and prefix length
+pub type Prefix = (u32, u8);
+
+fn mask(len: u8) -> u32 {
+    match len {
+        0 => 0,
+        len => u32::MAX << (32 - len as u32),
+    }
+}
+
+pub fn parse_prefix(s: &str) -> Option<Prefix> {
+    let (address, len) = match s.split_once('/') {
+        Some((address, len)) => (address, len.parse().ok()?),
+        None => (s, 32),
+    };
+    if len > 32 {
+        return None;
+    }
+    let address: Ipv4Addr = address.parse().ok()?;
+    Some((u32::from(address) & mask(len), len))
+}
+
+// Smallest set of prefixes covering first..=last
+fn range_prefixes(first: u32, last: u32) -> Vec<Prefix> {
+    let mut prefixes = Vec::new();
+    let mut start = first as u64;
+    let end = last as u64 + 1;
+    while start < end {
+        // Largest block aligned on start that doesn't run past the end
+        let mut size = if start == 0 {
+            1 << 32
+        } else {
+            start & start.wrapping_neg()
+        };
+        while start + size > end {
+            size >>= 1;
+        }
+        prefixes.push((start as u32, 32 - size.trailing_zeros() as u8));
+        start += size;
+    }
+    prefixes
+}
+
+// Prefixes of a list, with the number of lines that couldn't be read
+pub fn parse_list(text: &str, format: ListFormat) -> (Vec<Prefix>, usize) {
+    let mut prefixes = Vec::new();
+    let mut skipped = 0;
+    for line in text.lines() {
+        let line = match format {
+            ListFormat::List => line.split('#').next().unwrap_or(""),
+            ListFormat::Spamhaus => line.split(';').next().unwrap_or(""),
+            ListFormat::Ipset => line,
+        };
+        let mut fields = line.split_whitespace();
+        let entry = match format {
+            ListFormat::List | ListFormat::Spamhaus => fields.next(),
+            // add <set> <entry> [options], the create lines and others are skipped
+            ListFormat::Ipset => match fields.next() {
+                Some("add") => fields.nth(1),
+                _ => None,
+            },
+        };
+        let entry = match entry {
+            Some(entry) => entry,
+            None => continue,
+        };
+        match entry.split_once('-') {
+            Some((first, last)) => match (first.parse::<Ipv4Addr>(), last.parse::<Ipv4Addr>()) {
+                (Ok(first), Ok(last)) if first <= last => {
+                    prefixes.extend(range_prefixes(u32::from(first), u32::from(last)))
+                }
+                _ => skipped += 1,
+            },
+            None => match parse_prefix(entry) {
+                Some(prefix) => prefixes.push(prefix),
+                None => skipped += 1,
+            },
+        }
+    }
+    (prefixes, skipped)
+}
+
+// Drops duplicates and prefixes inside others, then merges sibling prefixes
+// into their parent until none are left
+pub fn coalesce(mut prefixes: Vec<Prefix>) -> Vec<Prefix> {
+    prefixes.sort_unstable();
+    let mut merged: Vec<Prefix> = Vec::with_capacity(prefixes.len());
+    for (network, len) in prefixes {
+        // Sorted by network then length, so a covering prefix comes first
+        if let Some(&(last, last_len)) = merged.last() {
+            if last_len <= len && network & mask(last_len) == last {
+                continue;
+            }
+        }
+        merged.push((network, len));
+        while merged.len() >= 2 {
+            let (b, b_len) = merged[merged.len() - 1];
+            let (a, a_len) = merged[merged.len() - 2];
+            let sibling = a_len == b_len
+                && a_len > 0
+                && a & mask(a_len - 1) == a
+                && b == a | (1 << (32 - a_len as u32));
+            if !sibling {
+                break;
+            }
+            merged.truncate(merged.len() - 2);
+            merged.push((a, a_len - 1));
+        }
+    }
+    merged
+}
+
+// Entries of a trie, the prefix and the id of the list it came from
+type Entries = Vec<(Prefix, u32)>;
+
+// Loads the lists into the BLOCKLIST tries. The one lookups don't use is
+// filled, then CONFIG_BLOCKLIST switches lookups over and the other is emptied.
+// The networks of blocked countries follow the lists, with the last id
+pub struct Blocklists {
+    lists: Vec<BlocklistConfig>,
+    geoip: Option<GeoipConfig>,
+    tries: Vec<LpmTrie<MapRefMut, u32, u32>>,
+    config: Array<MapRefMut, u32>,
+    // What each trie holds, to empty it later
+    loaded: [Entries; 2],
+    active: Option<usize>,
+    // Prefixes of each list and of the countries as last read, kept when one
+    // fails to load
+    last_good: Vec<Option<Vec<Prefix>>>,
+}
+
+impl Blocklists {
+    pub fn new(
+        bpf: &Bpf,
+        lists: Vec<BlocklistConfig>,
+        geoip: Option<GeoipConfig>,
+    ) -> Result<Self, anyhow::Error> {
+        let geoip = geoip.filter(|geoip| !geoip.block.is_empty());
+        Ok(Blocklists {
+            last_good: vec![None; lists.len() + geoip.iter().count()],
+            lists,
+            geoip,
+            tries: vec![
+                LpmTrie::try_from(bpf.map_mut("BLOCKLIST_0")?)?,
+                LpmTrie::try_from(bpf.map_mut("BLOCKLIST_1")?)?,
+            ],
+            config: Array::try_from(bpf.map_mut("CONFIG")?)?,
+            loaded: [Vec::new(), Vec::new()],
+            active: None,
+        })
+    }
+
+    fn read_lists(&mut self) -> Entries {
+        let mut entries = Vec::new();
+        for (index, list) in self.lists.iter().enumerate() {
+            let id = index as u32 + 1;
+            match fs::read_to_string(&list.path) {
+                Ok(text) => {
+                    let (prefixes, skipped) = parse_list(&text, list.format);
+                    if skipped > 0 {
+                        warn!(
+                            "Blocklist {}: skipped {} unreadable lines",
+                            list.name, skipped
+                        );
+                    }
+                    self.last_good[index] = Some(coalesce(prefixes));
+                }
+                Err(e) => warn!(
+                    "Blocklist {}: {}, keeping the previous entries",
+                    list.name, e
+                ),
+            }
+            if let Some(prefixes) = &self.last_good[index] {
+                entries.extend(prefixes.iter().map(|prefix| (*prefix, id)));
+            }
+        }
+        if let Some(geoip) = &self.geoip {
+            let index = self.lists.len();
+            match country_prefixes(geoip) {
+                Ok(prefixes) => self.last_good[index] = Some(coalesce(prefixes)),
+                Err(e) => warn!("GeoIP: {:#}, keeping the previous entries", e),
+            }
+            if let Some(prefixes) = &self.last_good[index] {
+                let id = index as u32 + 1;
+                entries.extend(prefixes.iter().map(|prefix| (*prefix, id)));
+            }
+        }
+        entries
+    }
+
+    fn names(&self) -> Vec<String> {
+        let geoip = self
+            .geoip
+            .as_ref()
+            .map(|geoip| format!("GeoIP {}", geoip.block.join(",")));
+        self.lists
+            .iter()
+            .map(|list| list.name.clone())
+            .chain(geoip)
+            .collect()
+    }
+
+    // Reads every list again and swaps the tries if anything changed
+    pub fn reload(&mut self) -> Result<(), anyhow::Error> {
+        let entries = self.read_lists();
+        if entries.len() > BLOCKLIST_ENTRIES as usize {
+            bail!(
+                "{} blocklist prefixes, the maps hold {}",
+                entries.len(),
+                BLOCKLIST_ENTRIES
+            );
+        }
+        if let Some(active) = self.active {
+            if self.loaded[active] == entries {
+                return Ok(());
+            }
+        }
+
+        let next = match self.active {
+            Some(0) => 1,
+            _ => 0,
+        };
+        for (inserted, &((network, len), id)) in entries.iter().enumerate() {
+            let key = Key::new(len as u32, network.to_be());
+            if let Err(e) = self.tries[next].insert(&key, id, 0) {
+                // Leaves the unused trie empty for the next attempt
+                for &((network, len), _) in &entries[..inserted] {
+                    let _ = self.tries[next].remove(&Key::new(len as u32, network.to_be()));
+                }
+                return Err(e.into());
+            }
+        }
+        self.loaded[next] = entries;
+        self.config.set(CONFIG_BLOCKLIST, next as u32 + 1, 0)?;
+        let previous = self.active.replace(next);
+
+        for (index, name) in self.names().iter().enumerate() {
+            let count = self.last_good[index].as_ref().map_or(0, Vec::len);
+            info!("Blocklist {}: {} ({} prefixes)", index + 1, name, count);
+        }
+
+        if let Some(previous) = previous {
+            // Lets programs that read the old CONFIG_BLOCKLIST value finish their lookup
+            thread::sleep(Duration::from_millis(10));
+            for ((network, len), _) in self.loaded[previous].drain(..) {
+                let key = Key::new(len as u32, network.to_be());
+                let _ = self.tries[previous].remove(&key);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Reads the lists and fills the tries on a blocking thread, as that waits for
+// lookups to switch and makes a syscall per entry
+async fn reload(mut blocklists: Blocklists) -> (Blocklists, Result<(), anyhow::Error>) {
+    match task::spawn_blocking(move || {
+        let result = blocklists.reload();
+        (blocklists, result)
+    })
+    .await
+    {
+        Ok(reloaded) => reloaded,
+        // The thread only fails by panicking, which is passed on
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+// Loads the lists now and again every interval, none when interval is zero
+pub async fn load_blocklists(
+    blocklists: Blocklists,
+    interval: Duration,
+) -> Result<(), anyhow::Error> {
+    let (mut blocklists, result) = reload(blocklists).await;
+    result?;
+    if interval.as_secs() == 0 {
+        return Ok(());
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let (reloaded, result) = reload(blocklists).await;
+            blocklists = reloaded;
+            if let Err(e) = result {
+                warn!("Blocklists not reloaded: {:#}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefix(s: &str) -> Prefix {
+        parse_prefix(s).unwrap()
+    }
+
+    fn prefixes(list: &[&str]) -> Vec<Prefix> {
+        list.iter().map(|s| prefix(s)).collect()
+    }
+
+    #[test]
+    fn list_reads_addresses_prefixes_and_ranges() {
+        let text = "# comment\n10.0.0.1\n\n192.0.2.0/24 # inline\n10.1.0.0-10.1.0.3\nnot an address\n10.0.0.0/33\n";
+        let (parsed, skipped) = parse_list(text, ListFormat::List);
+        assert_eq!(
+            parsed,
+            prefixes(&["10.0.0.1/32", "192.0.2.0/24", "10.1.0.0/30"])
+        );
+        assert_eq!(skipped, 2);
+    }
+
+    #[test]
+    fn list_masks_host_bits() {
+        let (parsed, _) = parse_list("192.0.2.77/24\n", ListFormat::List);
+        assert_eq!(parsed, vec![(u32::from(Ipv4Addr::new(192, 0, 2, 0)), 24)]);
+    }
+
+    #[test]
+    fn spamhaus_drops_the_sbl_ids() {
+        let text = "; Spamhaus DROP List\n1.10.16.0/20 ; SBL256894\n1.19.0.0/16 ; SBL434604\n";
+        let (parsed, skipped) = parse_list(text, ListFormat::Spamhaus);
+        assert_eq!(parsed, prefixes(&["1.10.16.0/20", "1.19.0.0/16"]));
+        assert_eq!(skipped, 0);
+    }
+
+    #[test]
+    fn ipset_reads_the_add_lines() {
+        let text = "create honeypot hash:net family inet hashsize 1024 maxelem 65536\n\
+                    add honeypot 198.51.100.0/24\n\
+                    add honeypot 203.0.113.9 timeout 300\n";
+        let (parsed, skipped) = parse_list(text, ListFormat::Ipset);
+        assert_eq!(parsed, prefixes(&["198.51.100.0/24", "203.0.113.9"]));
+        assert_eq!(skipped, 0);
+    }
+
+    #[test]
+    fn ranges_split_on_alignment() {
+        let first = u32::from(Ipv4Addr::new(10, 0, 0, 1));
+        let last = u32::from(Ipv4Addr::new(10, 0, 0, 6));
+        assert_eq!(
+            range_prefixes(first, last),
+            prefixes(&["10.0.0.1/32", "10.0.0.2/31", "10.0.0.4/31", "10.0.0.6/32"])
+        );
+    }
+
+    #[test]
+    fn ranges_from_zero() {
+        assert_eq!(range_prefixes(0, 0), prefixes(&["0.0.0.0/32"]));
+        assert_eq!(
+            range_prefixes(0, u32::from(Ipv4Addr::new(0, 255, 255, 255))),
+            prefixes(&["0.0.0.0/8"])
+        );
+        assert_eq!(
+            range_prefixes(0, u32::from(Ipv4Addr::new(1, 0, 0, 0))),
+            prefixes(&["0.0.0.0/8", "1.0.0.0/32"])
+        );
+        assert_eq!(range_prefixes(0, u32::MAX), prefixes(&["0.0.0.0/0"]));
+    }
+
+    #[test]
+    fn ranges_to_the_last_address() {
+        let first = u32::from(Ipv4Addr::new(255, 255, 255, 254));
+        assert_eq!(
+            range_prefixes(first, u32::MAX),
+            prefixes(&["255.255.255.254/31"])
+        );
+    }
+
+    #[test]
+    fn coalesce_merges_siblings_up() {
+        let merged = coalesce(prefixes(&[
+            "10.0.0.3",
+            "10.0.0.0",
+            "10.0.0.2",
+            "10.0.0.1",
+            "10.0.1.0/24",
+        ]));
+        assert_eq!(merged, prefixes(&["10.0.0.0/30", "10.0.1.0/24"]));
+        let merged = coalesce(prefixes(&["10.0.0.0/24", "10.0.1.0/24"]));
+        assert_eq!(merged, prefixes(&["10.0.0.0/23"]));
+    }
+
+    #[test]
+    fn coalesce_keeps_neighbours_that_are_not_siblings() {
+        let list = prefixes(&["10.0.1.0/24", "10.0.2.0/24"]);
+        assert_eq!(coalesce(list.clone()), list);
+    }
+
+    #[test]
+    fn coalesce_drops_duplicates_and_nested_prefixes() {
+        let merged = coalesce(prefixes(&[
+            "10.0.0.5",
+            "10.0.0.0/16",
+            "10.0.3.0/24",
+            "10.0.0.0/16",
+            "192.0.2.1",
+        ]));
+        assert_eq!(merged, prefixes(&["10.0.0.0/16", "192.0.2.1"]));
+    }
+
+    #[test]
+    fn coalesce_stops_at_the_default_route() {
+        assert_eq!(
+            coalesce(prefixes(&["0.0.0.0/1", "128.0.0.0/1"])),
+            prefixes(&["0.0.0.0/0"])
+        );
+        assert_eq!(
+            coalesce(prefixes(&["0.0.0.0/0", "10.0.0.0/8", "0.0.0.0/0"])),
+            prefixes(&["0.0.0.0/0"])
+        );
+    }
+}
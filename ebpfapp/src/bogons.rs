@@ -0,0 +1,97 @@
+use aya::maps::lpm_trie::{Key, LpmTrie};
+use aya::Bpf;
+use ebpfapp_common::{BogonType, BOGON_TYPES};
+use log::info;
+use nix::ifaddrs::getifaddrs;
+use nix::sys::socket::SockAddr;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr};
+
+use crate::metrics::KernelCounters;
+use crate::parser::ParserToString;
+
+// Source ranges that are never valid on the wire, whichever side they arrive on
+const MARTIANS: &[(Ipv4Addr, u32, BogonType)] = &[
+    (Ipv4Addr::new(0, 0, 0, 0), 8, BogonType::THISNET),
+    (Ipv4Addr::new(127, 0, 0, 0), 8, BogonType::LOOPBACK),
+    (Ipv4Addr::new(224, 0, 0, 0), 4, BogonType::MULTICAST),
+    (Ipv4Addr::new(240, 0, 0, 0), 4, BogonType::RESERVED),
+];
+
+// RFC1918 ranges, only bogons when the interface faces the internet
+const PRIVATE: &[(Ipv4Addr, u32)] = &[
+    (Ipv4Addr::new(10, 0, 0, 0), 8),
+    (Ipv4Addr::new(172, 16, 0, 0), 12),
+    (Ipv4Addr::new(192, 168, 0, 0), 16),
+];
+
+const BOGON_LIST: [BogonType; BOGON_TYPES as usize] = [
+    BogonType::PRIVATE,
+    BogonType::LOOPBACK,
+    BogonType::MULTICAST,
+    BogonType::THISNET,
+    BogonType::RESERVED,
+    BogonType::LOCAL,
+];
+
+// IPv4 addresses assigned to the interface, a packet arriving with one of these
+// as its source is spoofed
+pub fn interface_addresses(iface: &str) -> Result<Vec<Ipv4Addr>, anyhow::Error> {
+    let addresses = getifaddrs()?
+        .filter(|ifaddr| ifaddr.interface_name == iface)
+        .filter_map(|ifaddr| match ifaddr.address {
+            Some(SockAddr::Inet(inet)) => match inet.ip().to_std() {
+                IpAddr::V4(address) => Some(address),
+                IpAddr::V6(_) => None,
+            },
+            _ => None,
+        })
+        .collect();
+    Ok(addresses)
+}
+
+// The bogon prefixes of the interface and the list each one belongs to
+pub fn bogon_prefixes(
+    iface: &str,
+    wan: bool,
+) -> Result<Vec<(Ipv4Addr, u32, BogonType)>, anyhow::Error> {
+    let mut entries = MARTIANS.to_vec();
+    if wan {
+        entries.extend(
+            PRIVATE
+                .iter()
+                .map(|&(addr, len)| (addr, len, BogonType::PRIVATE)),
+        );
+    }
+    // Longest prefix wins, so our own address is reported as LOCAL even inside a private range
+    for addr in interface_addresses(iface)? {
+        entries.push((addr, 32, BogonType::LOCAL));
+    }
+    Ok(entries)
+}
+
+pub fn load_bogons(bpf: &Bpf, iface: &str, wan: bool) -> Result<(), anyhow::Error> {
+    let mut bogons: LpmTrie<_, u32, u32> = LpmTrie::try_from(bpf.map_mut("BOGONS")?)?;
+
+    for (addr, len, bogon) in bogon_prefixes(iface, wan)? {
+        // The trie compares keys byte by byte, so store them in network order
+        let key = Key::new(len, u32::from(addr).to_be());
+        bogons.insert(&key, bogon as u32, 0)?;
+        info!("Bogon {}/{} ({})", addr, len, bogon.to_str());
+    }
+    Ok(())
+}
+
+pub fn bogon_counters(bpf: &Bpf) -> Result<KernelCounters, anyhow::Error> {
+    let names = BOGON_LIST
+        .iter()
+        .map(|bogon| (*bogon as u32, bogon.to_str()))
+        .collect();
+    KernelCounters::new(
+        bpf,
+        "BOGON_COUNTERS",
+        "ebpfapp_bogon_drops_total",
+        "list",
+        names,
+    )
+}
@@ -0,0 +1,34 @@
+use aya::maps::Array;
+use aya::Bpf;
+use ebpfapp_common::{Check, CONFIG_CHECKS};
+use log::info;
+use std::convert::TryFrom;
+
+use crate::parser::ParserToString;
+
+// Checks enabled when none are given on the command line, dropping packets
+// that carry IP options is opt-in
+pub const DEFAULT_CHECKS: &str = "truncated,version,ihl,length,fragment,tcpflags";
+
+pub fn parse_check(s: &str) -> Result<Check, String> {
+    Ok(match s {
+        "truncated" => Check::TRUNCATED,
+        "version" => Check::VERSION,
+        "ihl" => Check::IHL,
+        "length" => Check::LENGTH,
+        "fragment" => Check::FRAGMENT,
+        "tcpflags" => Check::TCPFLAGS,
+        "options" => Check::OPTIONS,
+        _ => return Err(format!("unknown check {}", s)),
+    })
+}
+
+pub fn load_checks(bpf: &Bpf, checks: &[Check]) -> Result<(), anyhow::Error> {
+    let mut config: Array<_, u32> = Array::try_from(bpf.map_mut("CONFIG")?)?;
+    let mask = checks.iter().fold(0, |mask, check| mask | check.mask());
+    config.set(CONFIG_CHECKS, mask, 0)?;
+
+    let names: Vec<_> = checks.iter().map(|check| check.to_str()).collect();
+    info!("Sanity checks: {}", names.join(", "));
+    Ok(())
+}
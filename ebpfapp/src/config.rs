@@ -0,0 +1,194 @@
+use anyhow::{bail, Context};
+use serde::{Deserialize, Deserializer};
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+use crate::blocklist::BlocklistConfig;
+use crate::detect::DetectionConfig;
+use crate::geoip::GeoipConfig;
+use crate::icmp::parse_icmp_type;
+use crate::knock::KnockConfig;
+use crate::lb::ServiceConfig;
+use crate::nat::NatConfig;
+use crate::schedule::Schedule;
+use crate::watch::WatchConfig;
+use crate::Command;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    Drop,
+    Pass,
+    // Send the packets out of another interface, named by the rule
+    Redirect,
+    // Hand the packets to the AF_XDP consumer
+    Inspect,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IcmpAction {
+    Drop,
+    Pass,
+}
+
+// A static rule, e.g.
+//
+// [[rule]]
+// source = "10.0.0.5"
+// action = "drop"
+// vlan = 100
+#[derive(Debug, Clone, Deserialize)]
+pub struct RuleConfig {
+    pub source: Ipv4Addr,
+    pub action: RuleAction,
+    // Only match packets tagged with this VLAN ID
+    pub vlan: Option<u16>,
+    // Target of a redirect rule
+    pub interface: Option<String>,
+    // Only in effect while the schedule is active, always when missing
+    pub schedule: Option<Schedule>,
+}
+
+impl RuleConfig {
+    pub fn command(&self) -> Command {
+        match self.action {
+            RuleAction::Drop => Command::Block {
+                ip: self.source,
+                vlan: self.vlan,
+                ttl: None,
+            },
+            RuleAction::Pass => Command::Allow {
+                ip: self.source,
+                vlan: self.vlan,
+            },
+            RuleAction::Redirect => Command::Redirect {
+                ip: self.source,
+                vlan: self.vlan,
+                interface: self.interface.clone().unwrap_or_default(),
+            },
+            RuleAction::Inspect => Command::Inspect {
+                ip: self.source,
+                vlan: self.vlan,
+            },
+        }
+    }
+}
+
+// ICMP policy by type, e.g.
+//
+// [[icmp]]
+// type = "destination-unreachable"
+// code = 4
+// action = "pass"
+#[derive(Debug, Deserialize)]
+pub struct IcmpConfig {
+    // Name such as "echo-request" or the type number
+    #[serde(rename = "type", deserialize_with = "icmp_type")]
+    pub icmp_type: u8,
+    // Every code of the type when missing
+    pub code: Option<u8>,
+    pub action: IcmpAction,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NameOrNumber {
+    Number(u8),
+    Name(String),
+}
+
+fn icmp_type<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    match NameOrNumber::deserialize(deserializer)? {
+        NameOrNumber::Number(number) => Ok(number),
+        NameOrNumber::Name(name) => parse_icmp_type(&name).map_err(serde::de::Error::custom),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    #[serde(rename = "rule")]
+    pub rules: Vec<RuleConfig>,
+    pub icmp: Vec<IcmpConfig>,
+    #[serde(rename = "service")]
+    pub services: Vec<ServiceConfig>,
+    pub nat: Vec<NatConfig>,
+    pub detection: Option<DetectionConfig>,
+    #[serde(rename = "watch")]
+    pub watches: Vec<WatchConfig>,
+    #[serde(rename = "blocklist")]
+    pub blocklists: Vec<BlocklistConfig>,
+    #[serde(rename = "knock")]
+    pub knocks: Vec<KnockConfig>,
+    pub geoip: Option<GeoipConfig>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, anyhow::Error> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config {}", path.display()))?;
+        let config: Config = toml::from_str(&text)
+            .with_context(|| format!("failed to parse config {}", path.display()))?;
+        for rule in &config.rules {
+            if rule.action == RuleAction::Redirect && rule.interface.is_none() {
+                bail!("redirect rule for {} has no interface", rule.source);
+            }
+            if let Some(schedule) = &rule.schedule {
+                schedule
+                    .validate()
+                    .with_context(|| format!("invalid schedule for {}", rule.source))?;
+            }
+        }
+        for nat in &config.nat {
+            nat.validate()
+                .with_context(|| format!("invalid NAT for {}", nat.public))?;
+        }
+        for watch in &config.watches {
+            watch
+                .validate()
+                .with_context(|| format!("invalid watch {}", watch.name))?;
+        }
+        if let Some(geoip) = &config.geoip {
+            geoip.validate().context("invalid GeoIP config")?;
+        }
+        for knock in &config.knocks {
+            knock
+                .validate()
+                .with_context(|| format!("invalid knock gate {}", knock.name))?;
+        }
+        Ok(config)
+    }
+
+    pub fn scheduled_rules(&self) -> Vec<RuleConfig> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.schedule.is_some())
+            .cloned()
+            .collect()
+    }
+
+    // Commands that install the static rules through process_actions, scheduled
+    // rules are left to the scheduler
+    pub fn commands(&self) -> Vec<Command> {
+        let rules = self
+            .rules
+            .iter()
+            .filter(|rule| rule.schedule.is_none())
+            .map(RuleConfig::command);
+        let icmp = self.icmp.iter().map(|policy| match policy.action {
+            IcmpAction::Drop => Command::IcmpBlock {
+                icmp_type: policy.icmp_type,
+                code: policy.code,
+            },
+            IcmpAction::Pass => Command::IcmpAllow {
+                icmp_type: policy.icmp_type,
+                code: policy.code,
+            },
+        });
+        let services = self.services.iter().cloned().map(Command::Service);
+        let nat = self.nat.iter().cloned().map(Command::Nat);
+        rules.chain(icmp).chain(services).chain(nat).collect()
+    }
+}
@@ -0,0 +1,445 @@
+use aya::maps::{Array, HashMap as BpfHashMap, MapRef};
+use aya::Bpf;
+use ebpfapp_common::{DetectCount, ReachKey, CONFIG_DETECT};
+use log::{info, warn};
+use nix::time::{clock_gettime, ClockId};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use crate::Command;
+
+fn default_window() -> u64 {
+    10
+}
+
+fn default_block() -> u64 {
+    600
+}
+
+// Thresholds of the detectors, each one only runs when its threshold is set, e.g.
+//
+// [detection]
+// window = 10
+// block = 600
+// rate = 100000
+// ports = 100
+// hosts = 50
+#[derive(Debug, Clone, Deserialize)]
+pub struct DetectionConfig {
+    // Seconds of traffic the thresholds apply to
+    #[serde(default = "default_window")]
+    pub window: u64,
+    // Seconds a detected source stays blocked
+    #[serde(default = "default_block")]
+    pub block: u64,
+    // Packets a source may send in the window
+    pub rate: Option<u64>,
+    // Distinct TCP and UDP destination ports a source may reach in the window
+    pub ports: Option<usize>,
+    // Distinct destination hosts a source may reach in the window
+    pub hosts: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Detector {
+    Rate,
+    PortScan,
+    HostSweep,
+}
+
+impl fmt::Display for Detector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Detector::Rate => write!(f, "rate"),
+            Detector::PortScan => write!(f, "port scan"),
+            Detector::HostSweep => write!(f, "host sweep"),
+        }
+    }
+}
+
+// What a source did since the counters were last read
+#[derive(Debug, Default)]
+pub struct Observation {
+    pub packets: u64,
+    // TCP and UDP destination ports
+    pub ports: Vec<u16>,
+    pub hosts: Vec<Ipv4Addr>,
+}
+
+// What a source did in the window. Ports and hosts map to when they were last
+// reached, so they're only pruned once a threshold looks crossed
+struct Activity {
+    packets: VecDeque<(Instant, u64)>,
+    total: u64,
+    ports: HashMap<u16, Instant>,
+    hosts: HashMap<Ipv4Addr, Instant>,
+    last_seen: Instant,
+}
+
+#[derive(Debug)]
+pub struct Detection {
+    pub source: Ipv4Addr,
+    pub detector: Detector,
+    pub count: u64,
+}
+
+pub struct Detectors {
+    config: DetectionConfig,
+    window: Duration,
+    sources: HashMap<Ipv4Addr, Activity>,
+}
+
+impl Detectors {
+    pub fn new(config: DetectionConfig) -> Self {
+        Detectors {
+            window: Duration::from_secs(config.window),
+            config,
+            sources: HashMap::new(),
+        }
+    }
+
+    // Adds what the source did to its window and returns the first detector it
+    // sets off. The source starts over once detected
+    pub fn observe(
+        &mut self,
+        source: Ipv4Addr,
+        observation: &Observation,
+        now: Instant,
+    ) -> Option<Detection> {
+        let cutoff = now.checked_sub(self.window).unwrap_or(now);
+        let activity = self.sources.entry(source).or_insert_with(|| Activity {
+            packets: VecDeque::new(),
+            total: 0,
+            ports: HashMap::new(),
+            hosts: HashMap::new(),
+            last_seen: now,
+        });
+        activity.last_seen = now;
+
+        if observation.packets > 0 {
+            activity.packets.push_back((now, observation.packets));
+            activity.total += observation.packets;
+        }
+        while let Some(&(seen, packets)) = activity.packets.front() {
+            if seen > cutoff {
+                break;
+            }
+            activity.total -= packets;
+            activity.packets.pop_front();
+        }
+
+        let mut detection = None;
+        if let Some(rate) = self.config.rate {
+            if activity.total > rate {
+                detection = Some((Detector::Rate, activity.total));
+            }
+        }
+        if let Some(ports) = self.config.ports {
+            for port in &observation.ports {
+                activity.ports.insert(*port, now);
+            }
+            if activity.ports.len() > ports {
+                activity.ports.retain(|_, seen| *seen > cutoff);
+            }
+            if detection.is_none() && activity.ports.len() > ports {
+                detection = Some((Detector::PortScan, activity.ports.len() as u64));
+            }
+        }
+        if let Some(hosts) = self.config.hosts {
+            for host in &observation.hosts {
+                activity.hosts.insert(*host, now);
+            }
+            if activity.hosts.len() > hosts {
+                activity.hosts.retain(|_, seen| *seen > cutoff);
+            }
+            if detection.is_none() && activity.hosts.len() > hosts {
+                detection = Some((Detector::HostSweep, activity.hosts.len() as u64));
+            }
+        }
+
+        let (detector, count) = detection?;
+        self.sources.remove(&source);
+        Some(Detection {
+            source,
+            detector,
+            count,
+        })
+    }
+
+    // Forgets sources that sent nothing for a whole window
+    pub fn expire(&mut self, now: Instant) {
+        let window = self.window;
+        self.sources
+            .retain(|_, activity| now.duration_since(activity.last_seen) < window);
+    }
+}
+
+// bpf_ktime_get_ns time, which the DETECT_PORTS and DETECT_HOSTS entries are in
+fn ktime() -> Result<u64, anyhow::Error> {
+    let now = clock_gettime(ClockId::CLOCK_MONOTONIC)?;
+    Ok(now.tv_sec() as u64 * 1_000_000_000 + now.tv_nsec() as u64)
+}
+
+// Reads the per source counters the XDP program keeps for the detectors. As with
+// the aggregates, entries are left to the LRU and each read reports the change
+// since the one before
+pub struct Counters {
+    packets: BpfHashMap<MapRef, u32, u64>,
+    ports: BpfHashMap<MapRef, ReachKey, u64>,
+    hosts: BpfHashMap<MapRef, ReachKey, u64>,
+    seen: HashMap<u32, u64>,
+    // When the ports and hosts were last read
+    read: u64,
+}
+
+impl Counters {
+    pub fn new(bpf: &Bpf, config: &DetectionConfig) -> Result<Self, anyhow::Error> {
+        let mut counts = 0;
+        if config.rate.is_some() {
+            counts |= DetectCount::PACKETS.mask();
+        }
+        if config.ports.is_some() {
+            counts |= DetectCount::PORTS.mask();
+        }
+        if config.hosts.is_some() {
+            counts |= DetectCount::HOSTS.mask();
+        }
+        let mut map: Array<_, u32> = Array::try_from(bpf.map_mut("CONFIG")?)?;
+        map.set(CONFIG_DETECT, counts, 0)?;
+        Ok(Counters {
+            packets: BpfHashMap::try_from(bpf.map("DETECT_PACKETS")?)?,
+            ports: BpfHashMap::try_from(bpf.map("DETECT_PORTS")?)?,
+            hosts: BpfHashMap::try_from(bpf.map("DETECT_HOSTS")?)?,
+            seen: HashMap::new(),
+            read: ktime()?,
+        })
+    }
+
+    pub fn read(&mut self) -> Result<HashMap<Ipv4Addr, Observation>, anyhow::Error> {
+        let mut observations: HashMap<Ipv4Addr, Observation> = HashMap::new();
+        let mut seen = HashMap::new();
+        for (source, packets) in self.packets.iter().flatten() {
+            // An entry the LRU evicted and the program added again starts from zero
+            let new = match self.seen.get(&source) {
+                Some(last) if *last <= packets => packets - last,
+                _ => packets,
+            };
+            if new > 0 {
+                observations
+                    .entry(Ipv4Addr::from(source))
+                    .or_default()
+                    .packets = new;
+            }
+            seen.insert(source, packets);
+        }
+        self.seen = seen;
+
+        // Taken before the maps are walked, so an entry updated meanwhile is read
+        // again next time rather than missed
+        let read = ktime()?;
+        for (key, reached) in self.ports.iter().flatten() {
+            if reached >= self.read {
+                observations
+                    .entry(Ipv4Addr::from(key.source))
+                    .or_default()
+                    .ports
+                    .push(key.target as u16);
+            }
+        }
+        for (key, reached) in self.hosts.iter().flatten() {
+            if reached >= self.read {
+                observations
+                    .entry(Ipv4Addr::from(key.source))
+                    .or_default()
+                    .hosts
+                    .push(Ipv4Addr::from(key.target));
+            }
+        }
+        self.read = read;
+        Ok(observations)
+    }
+}
+
+// Runs the detectors on the per source counters of the XDP program every second
+// and blocks the sources they fire on for the configured time
+pub fn spawn_detectors(
+    bpf: &Bpf,
+    config: DetectionConfig,
+    tx: mpsc::Sender<Command>,
+) -> Result<(), anyhow::Error> {
+    let mut counters = Counters::new(bpf, &config)?;
+    info!(
+        "Detecting over {}s windows: rate {:?}, ports {:?}, hosts {:?}",
+        config.window, config.rate, config.ports, config.hosts
+    );
+    tokio::spawn(async move {
+        let ttl = Duration::from_secs(config.block);
+        let window = config.window;
+        let mut detectors = Detectors::new(config);
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            ticker.tick().await;
+            let observations = match counters.read() {
+                Ok(observations) => observations,
+                Err(e) => {
+                    warn!("Detector counters not read: {:#}", e);
+                    continue;
+                }
+            };
+            let now = Instant::now();
+            for (source, observation) in observations {
+                let detection = match detectors.observe(source, &observation, now) {
+                    Some(detection) => detection,
+                    None => continue,
+                };
+                warn!(
+                    "Detector {} fired for {}: {} in {}s, blocking for {}s",
+                    detection.detector,
+                    detection.source,
+                    detection.count,
+                    window,
+                    ttl.as_secs()
+                );
+                let block = Command::Block {
+                    ip: detection.source,
+                    vlan: None,
+                    ttl: Some(ttl),
+                };
+                if tx.send(block).await.is_err() {
+                    return;
+                }
+            }
+            detectors.expire(now);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(rate: Option<u64>, ports: Option<usize>, hosts: Option<usize>) -> DetectionConfig {
+        DetectionConfig {
+            window: 10,
+            block: 600,
+            rate,
+            ports,
+            hosts,
+        }
+    }
+
+    fn packets(packets: u64) -> Observation {
+        Observation {
+            packets,
+            ..Default::default()
+        }
+    }
+
+    const SOURCE: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+    #[test]
+    fn rate_fires_over_the_threshold() {
+        let mut detectors = Detectors::new(config(Some(100), None, None));
+        let start = Instant::now();
+        assert!(detectors.observe(SOURCE, &packets(60), start).is_none());
+        let detection = detectors
+            .observe(SOURCE, &packets(60), start + Duration::from_secs(1))
+            .expect("120 packets in the window");
+        assert_eq!(detection.detector, Detector::Rate);
+        assert_eq!(detection.count, 120);
+    }
+
+    #[test]
+    fn rate_forgets_packets_outside_the_window() {
+        let mut detectors = Detectors::new(config(Some(100), None, None));
+        let start = Instant::now();
+        for step in 0..30 {
+            let now = start + Duration::from_secs(step * 10);
+            assert!(detectors.observe(SOURCE, &packets(60), now).is_none());
+        }
+    }
+
+    #[test]
+    fn source_starts_over_once_detected() {
+        let mut detectors = Detectors::new(config(Some(100), None, None));
+        let start = Instant::now();
+        assert!(detectors.observe(SOURCE, &packets(101), start).is_some());
+        assert!(detectors.observe(SOURCE, &packets(1), start).is_none());
+    }
+
+    #[test]
+    fn port_scan_counts_distinct_ports() {
+        let mut detectors = Detectors::new(config(None, Some(10), None));
+        let start = Instant::now();
+        let same = Observation {
+            ports: vec![22; 50],
+            ..Default::default()
+        };
+        assert!(detectors.observe(SOURCE, &same, start).is_none());
+        let scan = Observation {
+            ports: (1..=11).collect(),
+            ..Default::default()
+        };
+        let detection = detectors
+            .observe(SOURCE, &scan, start)
+            .expect("12 distinct ports");
+        assert_eq!(detection.detector, Detector::PortScan);
+        assert_eq!(detection.count, 12);
+    }
+
+    #[test]
+    fn port_scan_drops_ports_outside_the_window() {
+        let mut detectors = Detectors::new(config(None, Some(10), None));
+        let start = Instant::now();
+        let first = Observation {
+            ports: (1..=8).collect(),
+            ..Default::default()
+        };
+        assert!(detectors.observe(SOURCE, &first, start).is_none());
+        let later = Observation {
+            ports: (100..=108).collect(),
+            ..Default::default()
+        };
+        let now = start + Duration::from_secs(11);
+        assert!(detectors.observe(SOURCE, &later, now).is_none());
+    }
+
+    #[test]
+    fn host_sweep_counts_distinct_hosts() {
+        let mut detectors = Detectors::new(config(None, None, Some(3)));
+        let sweep = Observation {
+            hosts: (1..=4).map(|i| Ipv4Addr::new(10, 0, 0, i)).collect(),
+            ..Default::default()
+        };
+        let detection = detectors
+            .observe(SOURCE, &sweep, Instant::now())
+            .expect("4 distinct hosts");
+        assert_eq!(detection.detector, Detector::HostSweep);
+        assert_eq!(detection.count, 4);
+    }
+
+    #[test]
+    fn sources_are_counted_apart() {
+        let mut detectors = Detectors::new(config(Some(100), None, None));
+        let now = Instant::now();
+        for i in 1..=10 {
+            let source = Ipv4Addr::new(192, 0, 2, i);
+            assert!(detectors.observe(source, &packets(60), now).is_none());
+        }
+    }
+
+    #[test]
+    fn idle_sources_expire() {
+        let mut detectors = Detectors::new(config(Some(100), None, None));
+        let start = Instant::now();
+        assert!(detectors.observe(SOURCE, &packets(60), start).is_none());
+        detectors.expire(start + Duration::from_secs(10));
+        assert!(detectors.sources.is_empty());
+    }
+}
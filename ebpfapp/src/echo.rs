@@ -0,0 +1,34 @@
+use aya::maps::{Array, HashMap};
+use aya::Bpf;
+use ebpfapp_common::{EchoCount, CONFIG_ECHO_RATE, ECHO_COUNTS};
+use log::info;
+use std::convert::TryFrom;
+
+use crate::bogons::interface_addresses;
+use crate::metrics::KernelCounters;
+use crate::parser::ParserToString;
+
+const ECHO_COUNT_LIST: [EchoCount; ECHO_COUNTS as usize] = [EchoCount::REPLIED, EchoCount::LIMITED];
+
+// Answer echo requests for the interface's own addresses from the XDP program,
+// at most rate replies a second
+pub fn load_echo(bpf: &Bpf, iface: &str, rate: u32) -> Result<(), anyhow::Error> {
+    let mut addresses: HashMap<_, u32, u8> = HashMap::try_from(bpf.map_mut("ECHO_ADDRESSES")?)?;
+    for addr in interface_addresses(iface)? {
+        addresses.insert(u32::from(addr), 1, 0)?;
+        info!("Answering echo requests for {}", addr);
+    }
+
+    let mut config: Array<_, u32> = Array::try_from(bpf.map_mut("CONFIG")?)?;
+    config.set(CONFIG_ECHO_RATE, rate, 0)?;
+    info!("Echo replies: {} a second", rate);
+    Ok(())
+}
+
+pub fn echo_counters(bpf: &Bpf) -> Result<KernelCounters, anyhow::Error> {
+    let names = ECHO_COUNT_LIST
+        .iter()
+        .map(|count| (*count as u32, count.to_str()))
+        .collect();
+    KernelCounters::new(bpf, "ECHO_COUNTERS", "ebpfapp_echo_total", "result", names)
+}
@@ -0,0 +1,348 @@
+use anyhow::Context;
+use std::fmt::Write;
+use std::fs;
+use std::net::Ipv4Addr;
+
+use crate::blocklist::{coalesce, parse_list, Prefix};
+use crate::bogons::bogon_prefixes;
+use crate::config::{Config, IcmpAction, RuleAction};
+use crate::geoip::country_prefixes;
+use crate::icmp::icmp_type_name;
+use crate::nat::NatConfig;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ExportFormat {
+    Nftables,
+    Iptables,
+}
+
+pub fn parse_format(s: &str) -> Result<ExportFormat, String> {
+    match s {
+        "nftables" | "nft" => Ok(ExportFormat::Nftables),
+        "iptables" => Ok(ExportFormat::Iptables),
+        _ => Err(format!(
+            "unknown export format {}, expected nftables or iptables",
+            s
+        )),
+    }
+}
+
+// Sources dropped for the same reason, one set in nftables
+struct DropGroup {
+    name: String,
+    // The blocklist it came from
+    comment: String,
+    prefixes: Vec<Prefix>,
+}
+
+struct IcmpFilter {
+    icmp_type: u8,
+    code: Option<u8>,
+    accept: bool,
+}
+
+// The parts of the policy netfilter can enforce, in the order the XDP program
+// applies them
+struct Policy {
+    drops: Vec<DropGroup>,
+    sources: Vec<Ipv4Addr>,
+    icmp: Vec<IcmpFilter>,
+    nat: Vec<NatConfig>,
+    // What is left out, printed as comments
+    notes: Vec<String>,
+}
+
+fn policy(config: &Config, bogons: bool, wan: bool, iface: &str) -> Result<Policy, anyhow::Error> {
+    let mut policy = Policy {
+        drops: Vec::new(),
+        sources: Vec::new(),
+        icmp: Vec::new(),
+        nat: config.nat.clone(),
+        notes: Vec::new(),
+    };
+    if bogons {
+        let prefixes = bogon_prefixes(iface, wan)?
+            .into_iter()
+            .map(|(address, len, _)| (u32::from(address), len as u8))
+            .collect();
+        policy.drops.push(DropGroup {
+            name: "bogons".to_string(),
+            comment: "bogons".to_string(),
+            prefixes: coalesce(prefixes),
+        });
+    }
+    for (index, list) in config.blocklists.iter().enumerate() {
+        let text = fs::read_to_string(&list.path)
+            .with_context(|| format!("failed to read blocklist {}", list.path.display()))?;
+        let (prefixes, skipped) = parse_list(&text, list.format);
+        if skipped > 0 {
+            policy.notes.push(format!(
+                "{} unreadable lines of blocklist {}",
+                skipped, list.name
+            ));
+        }
+        policy.drops.push(DropGroup {
+            name: format!("blocklist_{}", index + 1),
+            comment: format!("blocklist {}", list.name),
+            prefixes: coalesce(prefixes),
+        });
+    }
+    if let Some(geoip) = config
+        .geoip
+        .as_ref()
+        .filter(|geoip| !geoip.block.is_empty())
+    {
+        policy.drops.push(DropGroup {
+            name: "geoip".to_string(),
+            comment: format!("GeoIP {}", geoip.block.join(",")),
+            prefixes: coalesce(country_prefixes(geoip)?),
+        });
+    }
+
+    for rule in &config.rules {
+        if rule.schedule.is_some() {
+            policy
+                .notes
+                .push(format!("scheduled rule for {}", rule.source));
+            continue;
+        }
+        match (rule.action, rule.vlan) {
+            (RuleAction::Drop, None) => policy.sources.push(rule.source),
+            // Allowed sources go on to the ICMP policy like any other
+            (RuleAction::Pass, None) => {}
+            (_, Some(vlan)) => policy
+                .notes
+                .push(format!("rule for {} on VLAN {}", rule.source, vlan)),
+            (RuleAction::Redirect, None) => policy
+                .notes
+                .push(format!("redirect rule for {}", rule.source)),
+            (RuleAction::Inspect, None) => policy
+                .notes
+                .push(format!("inspect rule for {}", rule.source)),
+        }
+    }
+    // The XDP program looks up the code before the whole type
+    let mut icmp: Vec<_> = config.icmp.iter().collect();
+    icmp.sort_by_key(|policy| policy.code.is_none());
+    for entry in icmp {
+        policy.icmp.push(IcmpFilter {
+            icmp_type: entry.icmp_type,
+            code: entry.code,
+            accept: matches!(entry.action, IcmpAction::Pass),
+        });
+    }
+
+    for service in &config.services {
+        policy.notes.push(format!(
+            "service {}:{}/{}",
+            service.vip,
+            service.port,
+            service.protocol.name()
+        ));
+    }
+    for knock in &config.knocks {
+        policy.notes.push(format!("knock gate {}", knock.name));
+    }
+    if config.detection.is_some() || !config.watches.is_empty() {
+        policy
+            .notes
+            .push("sources blocked by detectors and watches".to_string());
+    }
+    Ok(policy)
+}
+
+fn prefix_string((network, len): Prefix) -> String {
+    match len {
+        32 => Ipv4Addr::from(network).to_string(),
+        len => format!("{}/{}", Ipv4Addr::from(network), len),
+    }
+}
+
+fn icmp_type_string(icmp_type: u8) -> String {
+    match icmp_type_name(icmp_type) {
+        Some(name) => name.to_string(),
+        None => icmp_type.to_string(),
+    }
+}
+
+fn nftables(policy: &Policy, iface: &str) -> String {
+    let mut out = String::new();
+    // Declaring the table first lets the delete succeed on the first load
+    let _ = writeln!(out, "table ip ebpfapp\ndelete table ip ebpfapp\n");
+    let _ = writeln!(out, "table ip ebpfapp {{");
+    for group in policy
+        .drops
+        .iter()
+        .filter(|group| !group.prefixes.is_empty())
+    {
+        let elements: Vec<_> = group.prefixes.iter().map(|p| prefix_string(*p)).collect();
+        let _ = writeln!(out, "\t# {}\n\tset {} {{", group.comment, group.name);
+        let _ = writeln!(
+            out,
+            "\t\ttype ipv4_addr\n\t\tflags interval\n\t\tauto-merge"
+        );
+        let _ = writeln!(out, "\t\telements = {{ {} }}", elements.join(", "));
+        let _ = writeln!(out, "\t}}\n");
+    }
+
+    let _ = writeln!(out, "\tchain filter {{");
+    let _ = writeln!(
+        out,
+        "\t\ttype filter hook prerouting priority raw; policy accept;"
+    );
+    let _ = writeln!(out, "\t\tiifname != \"{}\" accept", iface);
+    for group in policy
+        .drops
+        .iter()
+        .filter(|group| !group.prefixes.is_empty())
+    {
+        let _ = writeln!(out, "\t\tip saddr @{} drop", group.name);
+    }
+    for source in &policy.sources {
+        let _ = writeln!(out, "\t\tip saddr {} drop", source);
+    }
+    for icmp in &policy.icmp {
+        let _ = write!(out, "\t\ticmp type {}", icmp_type_string(icmp.icmp_type));
+        if let Some(code) = icmp.code {
+            let _ = write!(out, " icmp code {}", code);
+        }
+        let _ = writeln!(out, " {}", if icmp.accept { "accept" } else { "drop" });
+    }
+    let _ = writeln!(out, "\t}}");
+
+    if !policy.nat.is_empty() {
+        let _ = writeln!(out, "\n\tchain dnat {{");
+        let _ = writeln!(
+            out,
+            "\t\ttype nat hook prerouting priority dstnat; policy accept;"
+        );
+        for nat in &policy.nat {
+            let _ = write!(out, "\t\tiifname \"{}\" ip daddr {}", iface, nat.public);
+            match (nat.protocol, nat.port) {
+                (Some(protocol), Some(port)) => {
+                    let to_port = nat.to_port.unwrap_or(port);
+                    let protocol = protocol.name();
+                    let _ = writeln!(
+                        out,
+                        " {} dport {} dnat to {}:{}",
+                        protocol, port, nat.private, to_port
+                    );
+                }
+                _ => {
+                    let _ = writeln!(out, " dnat to {}", nat.private);
+                }
+            }
+        }
+        let _ = writeln!(out, "\t}}");
+        // Port forwards only need the replies translated, which conntrack does
+        let _ = writeln!(out, "\n\tchain snat {{");
+        let _ = writeln!(
+            out,
+            "\t\ttype nat hook postrouting priority srcnat; policy accept;"
+        );
+        for nat in policy.nat.iter().filter(|nat| nat.port.is_none()) {
+            let _ = writeln!(
+                out,
+                "\t\toifname \"{}\" ip saddr {} snat to {}",
+                iface, nat.private, nat.public
+            );
+        }
+        let _ = writeln!(out, "\t}}");
+    }
+    let _ = writeln!(out, "}}");
+    out
+}
+
+fn iptables(policy: &Policy, iface: &str) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "*raw\n:PREROUTING ACCEPT [0:0]\n:EBPFAPP - [0:0]");
+    let _ = writeln!(out, "-A PREROUTING -i {} -j EBPFAPP", iface);
+    for group in &policy.drops {
+        let _ = writeln!(out, "# {}", group.comment);
+        for prefix in &group.prefixes {
+            let (network, len) = *prefix;
+            let _ = writeln!(
+                out,
+                "-A EBPFAPP -s {}/{} -j DROP",
+                Ipv4Addr::from(network),
+                len
+            );
+        }
+    }
+    for source in &policy.sources {
+        let _ = writeln!(out, "-A EBPFAPP -s {}/32 -j DROP", source);
+    }
+    for icmp in &policy.icmp {
+        let icmp_type = match icmp.code {
+            Some(code) => format!("{}/{}", icmp.icmp_type, code),
+            None => icmp_type_string(icmp.icmp_type),
+        };
+        let target = if icmp.accept { "ACCEPT" } else { "DROP" };
+        let _ = writeln!(
+            out,
+            "-A EBPFAPP -p icmp -m icmp --icmp-type {} -j {}",
+            icmp_type, target
+        );
+    }
+    let _ = writeln!(out, "COMMIT");
+
+    if !policy.nat.is_empty() {
+        let _ = writeln!(
+            out,
+            "*nat\n:PREROUTING ACCEPT [0:0]\n:POSTROUTING ACCEPT [0:0]"
+        );
+        for nat in &policy.nat {
+            match (nat.protocol, nat.port) {
+                (Some(protocol), Some(port)) => {
+                    let protocol = protocol.name();
+                    let _ = writeln!(
+                        out,
+                        "-A PREROUTING -i {} -d {}/32 -p {} -m {} --dport {} -j DNAT --to-destination {}:{}",
+                        iface,
+                        nat.public,
+                        protocol,
+                        protocol,
+                        port,
+                        nat.private,
+                        nat.to_port.unwrap_or(port)
+                    );
+                }
+                _ => {
+                    let _ = writeln!(
+                        out,
+                        "-A PREROUTING -i {} -d {}/32 -j DNAT --to-destination {}",
+                        iface, nat.public, nat.private
+                    );
+                    let _ = writeln!(
+                        out,
+                        "-A POSTROUTING -o {} -s {}/32 -j SNAT --to-source {}",
+                        iface, nat.private, nat.public
+                    );
+                }
+            }
+        }
+        let _ = writeln!(out, "COMMIT");
+    }
+    out
+}
+
+// Renders the policy for netfilter, for hosts where the XDP program can't be
+// attached. Only what netfilter can do the same way is included
+pub fn export(
+    config: &Config,
+    format: ExportFormat,
+    iface: &str,
+    bogons: bool,
+    wan: bool,
+) -> Result<String, anyhow::Error> {
+    let policy = policy(config, bogons, wan, iface)?;
+    let mut out = format!("# ebpfapp policy for {}\n", iface);
+    for note in &policy.notes {
+        let _ = writeln!(out, "# Not exported: {}", note);
+    }
+    out += &match format {
+        ExportFormat::Nftables => nftables(&policy, iface),
+        ExportFormat::Iptables => iptables(&policy, iface),
+    };
+    Ok(out)
+}
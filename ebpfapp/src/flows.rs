@@ -0,0 +1,334 @@
+use ebpfapp_common::{PacketType, XdpAction};
+use log::{info, warn};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+
+use crate::aggregate::{Aggregates, Summary};
+
+// Field types, IPFIX information elements share the NetFlow v9 numbers below 128
+const OCTET_DELTA_COUNT: u16 = 1;
+const PACKET_DELTA_COUNT: u16 = 2;
+const PROTOCOL_IDENTIFIER: u16 = 4;
+const SOURCE_TRANSPORT_PORT: u16 = 7;
+const SOURCE_IPV4_ADDRESS: u16 = 8;
+const DESTINATION_TRANSPORT_PORT: u16 = 11;
+const DESTINATION_IPV4_ADDRESS: u16 = 12;
+const LAST_SWITCHED: u16 = 21;
+const FIRST_SWITCHED: u16 = 22;
+const FORWARDING_STATUS: u16 = 89;
+const FLOW_START_MILLISECONDS: u16 = 152;
+const FLOW_END_MILLISECONDS: u16 = 153;
+
+const TEMPLATE_ID: u16 = 256;
+// Keeps a message under the usual 1500 byte MTU
+const MAX_MESSAGE: usize = 1400;
+
+#[derive(Debug, Clone, Copy)]
+pub enum FlowFormat {
+    Ipfix,
+    Netflow9,
+}
+
+pub fn parse_format(s: &str) -> Result<FlowFormat, String> {
+    match s {
+        "ipfix" => Ok(FlowFormat::Ipfix),
+        "netflow9" | "v9" => Ok(FlowFormat::Netflow9),
+        _ => Err(format!(
+            "unknown flow format {}, expected ipfix or netflow9",
+            s
+        )),
+    }
+}
+
+impl FlowFormat {
+    fn version(self) -> u16 {
+        match self {
+            FlowFormat::Ipfix => 10,
+            FlowFormat::Netflow9 => 9,
+        }
+    }
+
+    fn header_len(self) -> usize {
+        match self {
+            FlowFormat::Ipfix => 16,
+            FlowFormat::Netflow9 => 20,
+        }
+    }
+
+    // Set ids of templates, IPFIX moved them from 0 to 2
+    fn template_set(self) -> u16 {
+        match self {
+            FlowFormat::Ipfix => 2,
+            FlowFormat::Netflow9 => 0,
+        }
+    }
+
+    fn fields(self) -> [(u16, u16); 10] {
+        let (start, end, len) = match self {
+            FlowFormat::Ipfix => (FLOW_START_MILLISECONDS, FLOW_END_MILLISECONDS, 8),
+            FlowFormat::Netflow9 => (FIRST_SWITCHED, LAST_SWITCHED, 4),
+        };
+        [
+            (SOURCE_IPV4_ADDRESS, 4),
+            (DESTINATION_IPV4_ADDRESS, 4),
+            (SOURCE_TRANSPORT_PORT, 2),
+            (DESTINATION_TRANSPORT_PORT, 2),
+            (PROTOCOL_IDENTIFIER, 1),
+            (OCTET_DELTA_COUNT, 8),
+            (PACKET_DELTA_COUNT, 8),
+            (FORWARDING_STATUS, 1),
+            (start, len),
+            (end, len),
+        ]
+    }
+
+    fn record_len(self) -> usize {
+        self.fields().iter().map(|(_, len)| *len as usize).sum()
+    }
+}
+
+// RFC 7270 forwarding status: the two top bits are forwarded, dropped or
+// consumed, the rest a reason code
+fn forwarding_status(action: XdpAction) -> u8 {
+    match action {
+        XdpAction::PASS | XdpAction::TX | XdpAction::REDIRECT => 0x40,
+        // Dropped, ACL deny
+        XdpAction::DROP => 0x81,
+        XdpAction::ABORTED => 0x80,
+    }
+}
+
+fn ip_protocol(summary: &Summary) -> u8 {
+    match summary.key.packet_type {
+        PacketType::TCP => 6,
+        PacketType::UDP => 17,
+        PacketType::ICMP => 1,
+        PacketType::UNKNOW => 0,
+    }
+}
+
+// Times of the interval the records cover, as the format wants them
+struct Window {
+    start_ms: u64,
+    end_ms: u64,
+    start_uptime: u32,
+    end_uptime: u32,
+}
+
+// Builds export packets, sequence numbers and uptime carry over between flushes
+pub struct Exporter {
+    format: FlowFormat,
+    domain: u32,
+    booted: Instant,
+    // Messages sent for NetFlow v9, data records sent for IPFIX
+    sequence: u32,
+}
+
+impl Exporter {
+    pub fn new(format: FlowFormat, domain: u32) -> Self {
+        Exporter {
+            format,
+            domain,
+            booted: Instant::now(),
+            sequence: 0,
+        }
+    }
+
+    fn header(&self, message: &mut Vec<u8>, count: u16, now: SystemTime) {
+        let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as u32;
+        message.extend_from_slice(&self.format.version().to_be_bytes());
+        match self.format {
+            // Length is filled in once the message is complete
+            FlowFormat::Ipfix => message.extend_from_slice(&0u16.to_be_bytes()),
+            FlowFormat::Netflow9 => {
+                message.extend_from_slice(&count.to_be_bytes());
+                let uptime = self.booted.elapsed().as_millis() as u32;
+                message.extend_from_slice(&uptime.to_be_bytes());
+            }
+        }
+        message.extend_from_slice(&secs.to_be_bytes());
+        message.extend_from_slice(&self.sequence.to_be_bytes());
+        message.extend_from_slice(&self.domain.to_be_bytes());
+    }
+
+    fn template(&self, message: &mut Vec<u8>) {
+        let fields = self.format.fields();
+        let len = 8 + fields.len() * 4;
+        message.extend_from_slice(&self.format.template_set().to_be_bytes());
+        message.extend_from_slice(&(len as u16).to_be_bytes());
+        message.extend_from_slice(&TEMPLATE_ID.to_be_bytes());
+        message.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+        for (field, len) in fields.iter() {
+            message.extend_from_slice(&field.to_be_bytes());
+            message.extend_from_slice(&len.to_be_bytes());
+        }
+    }
+
+    fn record(&self, message: &mut Vec<u8>, summary: &Summary, window: &Window) {
+        message.extend_from_slice(&summary.key.source.to_be_bytes());
+        message.extend_from_slice(&summary.key.destination.to_be_bytes());
+        message.extend_from_slice(&summary.key.source_port.to_be_bytes());
+        message.extend_from_slice(&summary.key.destination_port.to_be_bytes());
+        message.push(ip_protocol(summary));
+        message.extend_from_slice(&summary.bytes.to_be_bytes());
+        message.extend_from_slice(&summary.packets.to_be_bytes());
+        message.push(forwarding_status(summary.key.action));
+        match self.format {
+            FlowFormat::Ipfix => {
+                message.extend_from_slice(&window.start_ms.to_be_bytes());
+                message.extend_from_slice(&window.end_ms.to_be_bytes());
+            }
+            FlowFormat::Netflow9 => {
+                message.extend_from_slice(&window.start_uptime.to_be_bytes());
+                message.extend_from_slice(&window.end_uptime.to_be_bytes());
+            }
+        }
+    }
+
+    // One message per chunk of records that fits the MTU, each starting with the
+    // template so a collector that restarted picks it up on the next message
+    pub fn messages(&mut self, summaries: &[Summary], interval: Duration) -> Vec<Vec<u8>> {
+        let now = SystemTime::now();
+        let end_ms = now
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let end_uptime = self.booted.elapsed().as_millis() as u32;
+        let window = Window {
+            start_ms: end_ms.saturating_sub(interval.as_millis() as u64),
+            end_ms,
+            start_uptime: end_uptime.saturating_sub(interval.as_millis() as u32),
+            end_uptime,
+        };
+
+        let template_len = 8 + self.format.fields().len() * 4;
+        let per_message =
+            (MAX_MESSAGE - self.format.header_len() - template_len - 4) / self.format.record_len();
+        let mut messages = Vec::new();
+        for chunk in summaries.chunks(per_message) {
+            let mut message = Vec::with_capacity(MAX_MESSAGE);
+            // NetFlow v9 counts the template and the data records
+            self.header(&mut message, chunk.len() as u16 + 1, now);
+            self.template(&mut message);
+
+            let set_start = message.len();
+            message.extend_from_slice(&TEMPLATE_ID.to_be_bytes());
+            message.extend_from_slice(&0u16.to_be_bytes());
+            for summary in chunk {
+                self.record(&mut message, summary, &window);
+            }
+            // Sets are padded to 32 bits
+            while (message.len() - set_start) % 4 != 0 {
+                message.push(0);
+            }
+            let set_len = (message.len() - set_start) as u16;
+            message[set_start + 2..set_start + 4].copy_from_slice(&set_len.to_be_bytes());
+            if let FlowFormat::Ipfix = self.format {
+                let len = message.len() as u16;
+                message[2..4].copy_from_slice(&len.to_be_bytes());
+            }
+
+            self.sequence = match self.format {
+                FlowFormat::Ipfix => self.sequence.wrapping_add(chunk.len() as u32),
+                FlowFormat::Netflow9 => self.sequence.wrapping_add(1),
+            };
+            messages.push(message);
+        }
+        messages
+    }
+}
+
+// Sends the traffic of every flow seen in the interval to the collector
+pub fn export_flows(
+    mut aggregates: Aggregates,
+    mut exporter: Exporter,
+    collector: SocketAddr,
+    interval: Duration,
+) {
+    info!(
+        "Exporting flows to {} as {:?} every {}s",
+        collector,
+        exporter.format,
+        interval.as_secs()
+    );
+    tokio::spawn(async move {
+        let bind: SocketAddr = match collector {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = match UdpSocket::bind(bind).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("Flow export socket: {}", e);
+                return;
+            }
+        };
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let summaries = aggregates.flush();
+            for message in exporter.messages(&summaries, interval) {
+                if let Err(e) = socket.send_to(&message, collector).await {
+                    warn!("Flow export to {}: {}", collector, e);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregate::growth;
+    use ebpfapp_common::{AggregateKey, AggregateValue, PacketLog, Reason, TunnelType};
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+
+    // What the XDP program counts a packet of a source with a PASS rule under,
+    // sent on when the rule matches rather than through an event
+    fn pass_rule_packet() -> PacketLog {
+        PacketLog {
+            ipv4_address: u32::from(Ipv4Addr::new(192, 0, 2, 1)),
+            ipv4_destination: u32::from(Ipv4Addr::new(198, 51, 100, 1)),
+            action: XdpAction::PASS,
+            packet_type: PacketType::TCP,
+            reason: Reason::LISTED,
+            rule_id: 7,
+            vlan_id: 0,
+            tunnel: TunnelType::NONE,
+            outer_source: 0,
+            outer_destination: 0,
+            icmp_type: 0,
+            icmp_code: 0,
+            source_port: 40000,
+            destination_port: 443,
+            sample_rate: 1,
+        }
+    }
+
+    #[test]
+    fn pass_rule_sources_are_exported() {
+        let key = AggregateKey::new(&pass_rule_packet());
+        let mut seen = HashMap::new();
+        let counted = |packets, bytes| vec![(key, AggregateValue { packets, bytes })];
+        growth(&mut seen, counted(3, 180).into_iter());
+        let summaries = growth(&mut seen, counted(10, 15180).into_iter());
+        assert_eq!(summaries.len(), 1);
+
+        let mut exporter = Exporter::new(FlowFormat::Ipfix, 1);
+        let messages = exporter.messages(&summaries, Duration::from_secs(60));
+        assert_eq!(messages.len(), 1);
+        // After the message header, the template set and the data set header
+        let record = &messages[0][16 + 48 + 4..];
+        assert_eq!(record[0..4], [192, 0, 2, 1]);
+        assert_eq!(record[8..10], 40000u16.to_be_bytes());
+        assert_eq!(record[10..12], 443u16.to_be_bytes());
+        assert_eq!(record[12], 6);
+        assert_eq!(record[13..21], 15000u64.to_be_bytes());
+        assert_eq!(record[21..29], 7u64.to_be_bytes());
+        // Forwarded
+        assert_eq!(record[29], 0x40);
+    }
+}
@@ -0,0 +1,53 @@
+use aya::maps::Array;
+use aya::Bpf;
+use ebpfapp_common::{FragmentCount, FragmentPolicy, CONFIG_FRAGMENTS};
+use log::info;
+use std::convert::TryFrom;
+
+use crate::metrics::KernelCounters;
+use crate::parser::ParserToString;
+
+const FRAGMENT_COUNT_LIST: [FragmentCount; 5] = [
+    FragmentCount::FIRST,
+    FragmentCount::LATER,
+    FragmentCount::DROPPED,
+    FragmentCount::TINY,
+    FragmentCount::OVERLAP,
+];
+
+pub fn parse_policy(s: &str) -> Result<FragmentPolicy, String> {
+    Ok(match s {
+        "drop" => FragmentPolicy::DROP,
+        "tiny" => FragmentPolicy::TINY,
+        "overlap" => FragmentPolicy::OVERLAP,
+        _ => return Err(format!("unknown fragment policy {}", s)),
+    })
+}
+
+pub fn load_policies(bpf: &Bpf, policies: &[FragmentPolicy]) -> Result<(), anyhow::Error> {
+    let mut config: Array<_, u32> = Array::try_from(bpf.map_mut("CONFIG")?)?;
+    let mask = policies.iter().fold(0, |mask, policy| mask | policy.mask());
+    config.set(CONFIG_FRAGMENTS, mask, 0)?;
+
+    if policies.is_empty() {
+        info!("Fragments: PASS");
+    } else {
+        let names: Vec<_> = policies.iter().map(|policy| policy.to_str()).collect();
+        info!("Fragments: {}", names.join(", "));
+    }
+    Ok(())
+}
+
+pub fn fragment_counters(bpf: &Bpf) -> Result<KernelCounters, anyhow::Error> {
+    let names = FRAGMENT_COUNT_LIST
+        .iter()
+        .map(|count| (*count as u32, count.to_str()))
+        .collect();
+    KernelCounters::new(
+        bpf,
+        "FRAGMENT_COUNTERS",
+        "ebpfapp_fragments_total",
+        "kind",
+        names,
+    )
+}
@@ -0,0 +1,105 @@
+use anyhow::{bail, Context};
+use ipnetwork::{IpNetwork, Ipv4Network};
+use maxminddb::Reader;
+use serde::Deserialize;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+
+use crate::blocklist::Prefix;
+
+// A local MaxMind country database, GeoLite2-Country or compatible, and the
+// countries whose networks are dropped, e.g.
+//
+// [geoip]
+// database = "/var/lib/GeoIP/GeoLite2-Country.mmdb"
+// block = ["KP", "RU"]
+#[derive(Debug, Clone, Deserialize)]
+pub struct GeoipConfig {
+    pub database: PathBuf,
+    // ISO 3166 codes, events are only annotated when empty
+    #[serde(default)]
+    pub block: Vec<String>,
+}
+
+impl GeoipConfig {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        for code in &self.block {
+            if code.len() != 2 || !code.chars().all(|c| c.is_ascii_alphabetic()) {
+                bail!("{} is not a two letter country code", code);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Only the parts of a record that are read, the names are skipped
+#[derive(Deserialize)]
+struct Country<'a> {
+    iso_code: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct Record<'a> {
+    #[serde(borrow)]
+    country: Option<Country<'a>>,
+    // Where the network is registered, for networks without a country of their own
+    #[serde(borrow)]
+    registered_country: Option<Country<'a>>,
+}
+
+impl<'a> Record<'a> {
+    fn iso_code(&self) -> Option<&'a str> {
+        self.country
+            .as_ref()
+            .and_then(|country| country.iso_code)
+            .or_else(|| {
+                self.registered_country
+                    .as_ref()
+                    .and_then(|country| country.iso_code)
+            })
+    }
+}
+
+pub struct Countries {
+    reader: Reader<Vec<u8>>,
+}
+
+impl Countries {
+    pub fn open(path: &Path) -> Result<Countries, anyhow::Error> {
+        let reader = Reader::open_readfile(path)
+            .with_context(|| format!("failed to open GeoIP database {}", path.display()))?;
+        Ok(Countries { reader })
+    }
+
+    // ISO code of the address's country, as two ASCII letters
+    pub fn lookup(&self, address: Ipv4Addr) -> Option<[u8; 2]> {
+        let record: Record = self.reader.lookup(IpAddr::V4(address)).ok()?;
+        match record.iso_code()?.as_bytes() {
+            &[a, b] => Some([a, b]),
+            _ => None,
+        }
+    }
+
+    // Every IPv4 network of the countries, walking the whole tree of the database
+    pub fn prefixes(&self, codes: &[String]) -> Result<Vec<Prefix>, anyhow::Error> {
+        let all = IpNetwork::V4(Ipv4Network::new(Ipv4Addr::UNSPECIFIED, 0)?);
+        let mut prefixes = Vec::new();
+        for item in self.reader.within::<Record>(all)? {
+            let item = item?;
+            let listed = match item.info.iso_code() {
+                Some(iso_code) => codes.iter().any(|code| code.eq_ignore_ascii_case(iso_code)),
+                None => false,
+            };
+            if let (true, IpNetwork::V4(network)) = (listed, item.ip_net) {
+                prefixes.push((u32::from(network.network()), network.prefix()));
+            }
+        }
+        Ok(prefixes)
+    }
+}
+
+// Networks of the blocked countries, read from the database again on every call
+// so an updated file is picked up
+pub fn country_prefixes(config: &GeoipConfig) -> Result<Vec<Prefix>, anyhow::Error> {
+    Countries::open(&config.database)?.prefixes(&config.block)
+}
@@ -0,0 +1,41 @@
+use aya::Bpf;
+
+use crate::metrics::KernelCounters;
+
+// ICMP types that can be named in the config, the rest are given by number
+const ICMP_TYPE_NAMES: &[(u8, &str)] = &[
+    (0, "echo-reply"),
+    (3, "destination-unreachable"),
+    (4, "source-quench"),
+    (5, "redirect"),
+    (8, "echo-request"),
+    (9, "router-advertisement"),
+    (10, "router-solicitation"),
+    (11, "time-exceeded"),
+    (12, "parameter-problem"),
+    (13, "timestamp-request"),
+    (14, "timestamp-reply"),
+];
+
+pub fn icmp_type_name(icmp_type: u8) -> Option<&'static str> {
+    ICMP_TYPE_NAMES
+        .iter()
+        .find(|(number, _)| *number == icmp_type)
+        .map(|(_, name)| *name)
+}
+
+pub fn parse_icmp_type(s: &str) -> Result<u8, String> {
+    if let Some((number, _)) = ICMP_TYPE_NAMES.iter().find(|(_, name)| *name == s) {
+        return Ok(*number);
+    }
+    s.parse().map_err(|_| format!("unknown ICMP type {}", s))
+}
+
+// Every ICMP packet is counted by type, only named types are exported
+pub fn icmp_counters(bpf: &Bpf) -> Result<KernelCounters, anyhow::Error> {
+    let names = ICMP_TYPE_NAMES
+        .iter()
+        .map(|&(number, name)| (number as u32, name))
+        .collect();
+    KernelCounters::new(bpf, "ICMP_COUNTERS", "ebpfapp_icmp_total", "type", names)
+}
@@ -0,0 +1,868 @@
+use anyhow::{bail, Context};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+
+use crate::blocklist::{parse_prefix, Prefix};
+use crate::icmp::{icmp_type_name, parse_icmp_type};
+
+// Netfilter hooks, the built-in chains of iptables
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Hook {
+    Prerouting,
+    Input,
+    Forward,
+    Output,
+    Postrouting,
+}
+
+impl Hook {
+    fn parse(s: &str) -> Option<Hook> {
+        match s.to_ascii_lowercase().as_str() {
+            "prerouting" => Some(Hook::Prerouting),
+            "input" => Some(Hook::Input),
+            "forward" => Some(Hook::Forward),
+            "output" => Some(Hook::Output),
+            "postrouting" => Some(Hook::Postrouting),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Verdict {
+    Accept,
+    Drop,
+    Reject,
+    Dnat(Ipv4Addr, Option<u16>),
+    Snat(Ipv4Addr),
+    // Jumps, logging and every other target
+    Other(String),
+}
+
+// A rule of either ruleset, reduced to the matches that can be translated
+#[derive(Debug, Default)]
+struct ForeignRule {
+    // The rule as written, or where it is for nft
+    origin: String,
+    // The filter or nat table type of the chain, for nft the chain type
+    nat: bool,
+    hook: Option<Hook>,
+    source: Option<Prefix>,
+    destination: Option<Prefix>,
+    // Input and output interfaces of the rule
+    interface: Option<String>,
+    out_interface: Option<String>,
+    protocol: Option<String>,
+    source_port: Option<u16>,
+    port: Option<u16>,
+    icmp_type: Option<String>,
+    verdict: Option<Verdict>,
+    // Matches without an equivalent, the rule can't be translated when any are set
+    unsupported: Vec<String>,
+}
+
+// A chain with a default DROP, which the config can't express
+struct Policy {
+    origin: String,
+    hook: Option<Hook>,
+    drop: bool,
+}
+
+#[derive(Default)]
+pub struct Import {
+    // Interface the XDP program is attached to, the only one it sees packets of
+    iface: String,
+    rules: Vec<String>,
+    // Lines of the list file for prefix sources that are dropped
+    blocklist: Vec<String>,
+    icmp: Vec<String>,
+    nat: Vec<String>,
+    // (private, public) of the [[nat]] entries, SNAT rules back to them are covered
+    nat_pairs: Vec<(Ipv4Addr, Ipv4Addr)>,
+    // Sources and ICMP types translated so far, with whether they pass and the
+    // origin of their rule. Netfilter takes the first rule that matches, so later
+    // rules for them never apply
+    sources: Vec<(Prefix, bool, String)>,
+    icmp_types: Vec<(u8, Option<u8>, String)>,
+    // Origin and why each rule wasn't translated
+    pub untranslated: Vec<(String, String)>,
+    pub translated: usize,
+}
+
+// Splits a line of iptables-save into words, keeping quoted strings together
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '\\' if quoted => word.extend(chars.next()),
+            c if c.is_whitespace() && !quoted => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            c => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+fn parse_target(s: &str) -> Option<(Ipv4Addr, Option<u16>)> {
+    // A range of addresses or ports can't be expressed, only a single one
+    match s.split_once(':') {
+        Some((address, port)) => Some((address.parse().ok()?, Some(port.parse().ok()?))),
+        None => Some((s.parse().ok()?, None)),
+    }
+}
+
+fn parse_iptables(text: &str) -> (Vec<ForeignRule>, Vec<Policy>) {
+    let mut rules = Vec::new();
+    let mut policies = Vec::new();
+    let mut table = String::from("filter");
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let origin = format!("line {}: {}", number + 1, line);
+        if let Some(name) = line.strip_prefix('*') {
+            table = name.to_string();
+            continue;
+        }
+        // :CHAIN POLICY [packets:bytes]
+        if let Some(chain) = line.strip_prefix(':') {
+            let mut fields = chain.split_whitespace();
+            if let (Some(chain), Some(policy)) = (fields.next(), fields.next()) {
+                policies.push(Policy {
+                    origin,
+                    hook: Hook::parse(chain).filter(|_| table == "filter"),
+                    drop: policy == "DROP",
+                });
+            }
+            continue;
+        }
+        let mut words = split_words(line);
+        // Counters given with iptables-save -c come first
+        if matches!(words.first(), Some(word) if word.starts_with('[')) {
+            words.remove(0);
+        }
+        if words.first().map(String::as_str) != Some("-A") {
+            continue;
+        }
+
+        let mut rule = ForeignRule {
+            origin,
+            nat: table == "nat",
+            ..Default::default()
+        };
+        let mut words = words.iter().map(String::as_str).peekable();
+        while let Some(word) = words.next() {
+            let mut value = || words.next().unwrap_or("");
+            match word {
+                "-A" => {
+                    let chain = value();
+                    rule.hook = Hook::parse(chain).filter(|_| chain == chain.to_uppercase());
+                    if rule.hook.is_none() {
+                        rule.unsupported.push(format!("chain {}", chain));
+                    }
+                }
+                "-s" | "--source" => match parse_prefix(value()) {
+                    Some(prefix) => rule.source = Some(prefix),
+                    None => rule.unsupported.push("source".to_string()),
+                },
+                "-d" | "--destination" => match parse_prefix(value()) {
+                    Some(prefix) => rule.destination = Some(prefix),
+                    None => rule.unsupported.push("destination".to_string()),
+                },
+                "-p" | "--protocol" => rule.protocol = Some(value().to_ascii_lowercase()),
+                "--sport" | "--source-port" => match value().parse() {
+                    Ok(port) => rule.source_port = Some(port),
+                    Err(_) => rule.unsupported.push("source port range".to_string()),
+                },
+                "--dport" | "--destination-port" => match value().parse() {
+                    Ok(port) => rule.port = Some(port),
+                    Err(_) => rule.unsupported.push("port range".to_string()),
+                },
+                "!" => rule.unsupported.push("negation".to_string()),
+                "--icmp-type" => rule.icmp_type = Some(value().to_string()),
+                "-i" | "--in-interface" => rule.interface = Some(value().to_string()),
+                "-o" | "--out-interface" => rule.out_interface = Some(value().to_string()),
+                "-m" | "--match" => match value() {
+                    "tcp" | "udp" | "icmp" | "comment" => {}
+                    module => rule.unsupported.push(format!("-m {}", module)),
+                },
+                "--comment" | "--reject-with" => {
+                    value();
+                }
+                "-j" | "--jump" => {
+                    rule.verdict = Some(match value() {
+                        "ACCEPT" => Verdict::Accept,
+                        "DROP" => Verdict::Drop,
+                        "REJECT" => Verdict::Reject,
+                        "DNAT" | "SNAT" => Verdict::Other(String::new()),
+                        target => Verdict::Other(target.to_string()),
+                    })
+                }
+                "--to-destination" | "--to-source" => {
+                    let target = parse_target(value());
+                    rule.verdict = match (word, target) {
+                        ("--to-destination", Some((address, port))) => {
+                            Some(Verdict::Dnat(address, port))
+                        }
+                        ("--to-source", Some((address, None))) => Some(Verdict::Snat(address)),
+                        _ => Some(Verdict::Other("NAT to a range".to_string())),
+                    };
+                }
+                option => {
+                    rule.unsupported.push(option.to_string());
+                    // Skips the values of the option, its negation included
+                    while matches!(words.peek(), Some(next) if !next.starts_with('-')) {
+                        words.next();
+                    }
+                }
+            }
+        }
+        rules.push(rule);
+    }
+    (rules, policies)
+}
+
+fn parse_address(value: &Value) -> Option<Prefix> {
+    match value {
+        Value::String(address) => parse_prefix(address),
+        Value::Object(object) => {
+            let prefix = object.get("prefix")?;
+            let address = prefix.get("addr")?.as_str()?;
+            let len = prefix.get("len")?.as_u64()?;
+            parse_prefix(&format!("{}/{}", address, len))
+        }
+        _ => None,
+    }
+}
+
+// One match expression of an nft rule, e.g. ip saddr 10.0.0.0/8 or tcp dport 22
+fn parse_match(rule: &mut ForeignRule, expression: &Value) -> Result<(), String> {
+    let op = expression.get("op").and_then(Value::as_str).unwrap_or("==");
+    let left = &expression["left"];
+    let right = &expression["right"];
+    if op != "==" {
+        return Err(format!("{} match", op));
+    }
+    if let Some(payload) = left.get("payload") {
+        let protocol = payload.get("protocol").and_then(Value::as_str);
+        let field = payload.get("field").and_then(Value::as_str);
+        match (protocol, field) {
+            (Some("ip"), Some("saddr")) => {
+                rule.source = Some(parse_address(right).ok_or("source set")?)
+            }
+            (Some("ip"), Some("daddr")) => {
+                rule.destination = Some(parse_address(right).ok_or("destination set")?)
+            }
+            (Some("ip"), Some("protocol")) => {
+                rule.protocol = Some(right.as_str().ok_or("protocol set")?.to_string())
+            }
+            (Some(protocol @ ("tcp" | "udp")), Some(field @ ("sport" | "dport"))) => {
+                rule.protocol = Some(protocol.to_string());
+                let port = right.as_u64().and_then(|port| u16::try_from(port).ok());
+                let port = port.ok_or("port range or set")?;
+                if field == "sport" {
+                    rule.source_port = Some(port);
+                } else {
+                    rule.port = Some(port);
+                }
+            }
+            (Some("icmp"), Some("type")) => {
+                rule.protocol = Some("icmp".to_string());
+                rule.icmp_type = Some(match right {
+                    Value::String(name) => name.clone(),
+                    Value::Number(number) => number.to_string(),
+                    _ => return Err("ICMP type set".to_string()),
+                });
+            }
+            (protocol, field) => {
+                return Err(format!(
+                    "{} {}",
+                    protocol.unwrap_or("?"),
+                    field.unwrap_or("?")
+                ))
+            }
+        }
+        return Ok(());
+    }
+    match left
+        .get("meta")
+        .and_then(|meta| meta.get("key"))
+        .and_then(Value::as_str)
+    {
+        Some("l4proto") => {
+            rule.protocol = Some(right.as_str().ok_or("protocol set")?.to_string());
+            Ok(())
+        }
+        Some("iifname") | Some("iif") => {
+            rule.interface = Some(right.as_str().ok_or("interface set")?.to_string());
+            Ok(())
+        }
+        Some("oifname") | Some("oif") => {
+            rule.out_interface = Some(right.as_str().ok_or("interface set")?.to_string());
+            Ok(())
+        }
+        Some(key) => Err(format!("meta {}", key)),
+        None => Err(left.to_string()),
+    }
+}
+
+fn parse_nft(text: &str) -> Result<(Vec<ForeignRule>, Vec<Policy>), anyhow::Error> {
+    let ruleset: Value = serde_json::from_str(text)?;
+    let objects = match ruleset.get("nftables").and_then(Value::as_array) {
+        Some(objects) => objects,
+        None => bail!("no nftables array, expected the output of nft -j list ruleset"),
+    };
+
+    // Type and hook of each base chain by family, table and name
+    let mut chains = HashMap::new();
+    let mut policies = Vec::new();
+    for chain in objects.iter().filter_map(|object| object.get("chain")) {
+        let name = |key: &str| chain.get(key).and_then(Value::as_str).unwrap_or("");
+        let hook = chain
+            .get("hook")
+            .and_then(Value::as_str)
+            .and_then(Hook::parse);
+        let nat = name("type") == "nat";
+        let key = (name("family"), name("table"), name("name"));
+        if let Some(policy) = chain.get("policy").and_then(Value::as_str) {
+            policies.push(Policy {
+                origin: format!("{} {} {}", key.0, key.1, key.2),
+                hook: hook.filter(|_| !nat),
+                drop: policy == "drop",
+            });
+        }
+        chains.insert(key, (nat, hook));
+    }
+
+    let mut rules = Vec::new();
+    for object in objects.iter().filter_map(|object| object.get("rule")) {
+        let name = |key: &str| object.get(key).and_then(Value::as_str).unwrap_or("");
+        let (family, table, chain) = (name("family"), name("table"), name("chain"));
+        let handle = object.get("handle").and_then(Value::as_u64).unwrap_or(0);
+        let mut rule = ForeignRule {
+            origin: format!("{} {} {} handle {}", family, table, chain, handle),
+            ..Default::default()
+        };
+        match chains.get(&(family, table, chain)) {
+            Some(&(nat, Some(hook))) => {
+                rule.nat = nat;
+                rule.hook = Some(hook);
+            }
+            _ => rule.unsupported.push(format!("chain {}", chain)),
+        }
+        if family != "ip" && family != "inet" {
+            rule.unsupported.push(format!("family {}", family));
+        }
+        let expressions = object.get("expr").and_then(Value::as_array);
+        for expression in expressions.into_iter().flatten() {
+            let (kind, value) = match expression.as_object().and_then(|e| e.iter().next()) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let verdict = match kind.as_str() {
+                "match" => {
+                    if let Err(e) = parse_match(&mut rule, value) {
+                        rule.unsupported.push(e);
+                    }
+                    continue;
+                }
+                "counter" => continue,
+                "accept" => Verdict::Accept,
+                "drop" => Verdict::Drop,
+                "reject" => Verdict::Reject,
+                "dnat" | "snat" => {
+                    let address = value.get("addr").and_then(Value::as_str);
+                    let address = address.and_then(|address| address.parse().ok());
+                    let port = value.get("port").map(|port| port.as_u64());
+                    match (kind.as_str(), address, port) {
+                        ("dnat", Some(address), None) => Verdict::Dnat(address, None),
+                        ("dnat", Some(address), Some(Some(port))) if port <= u16::MAX as u64 => {
+                            Verdict::Dnat(address, Some(port as u16))
+                        }
+                        ("snat", Some(address), None) => Verdict::Snat(address),
+                        _ => Verdict::Other(format!("{} to a range", kind)),
+                    }
+                }
+                other => Verdict::Other(other.to_string()),
+            };
+            rule.verdict = Some(verdict);
+        }
+        rules.push(rule);
+    }
+    Ok((rules, policies))
+}
+
+// Whether the first prefix covers all of the second
+fn contains((network, len): Prefix, (inner, inner_len): Prefix) -> bool {
+    len <= inner_len && (len == 0 || inner >> (32 - len as u32) == network >> (32 - len as u32))
+}
+
+// The line of iptables-save or the handle of nft, without the rule itself
+fn short(origin: &str) -> &str {
+    origin.split(':').next().unwrap_or(origin)
+}
+
+fn host(prefix: Option<Prefix>) -> Option<Ipv4Addr> {
+    match prefix {
+        Some((address, 32)) => Some(Ipv4Addr::from(address)),
+        _ => None,
+    }
+}
+
+impl Import {
+    // Reads an iptables-save dump or the JSON of nft -j list ruleset
+    pub fn from_file(path: &Path, iface: &str) -> Result<Import, anyhow::Error> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Import::from_text(&text, iface)
+            .with_context(|| format!("failed to parse {}", path.display()))
+    }
+
+    fn from_text(text: &str, iface: &str) -> Result<Import, anyhow::Error> {
+        let (rules, policies) = if text.trim_start().starts_with('{') {
+            parse_nft(text)?
+        } else {
+            parse_iptables(text)
+        };
+
+        let mut import = Import {
+            iface: iface.to_string(),
+            ..Default::default()
+        };
+        for policy in policies {
+            if policy.drop && matches!(policy.hook, Some(Hook::Input) | Some(Hook::Forward)) {
+                import.untranslated.push((
+                    policy.origin,
+                    "default DROP policy, sources without a rule pass".to_string(),
+                ));
+            }
+        }
+        // The DNAT rules first, so the SNAT rules of the same pairs are known
+        let (dnat, other): (Vec<_>, Vec<_>) = rules
+            .into_iter()
+            .partition(|rule| matches!(rule.verdict, Some(Verdict::Dnat(..))));
+        for rule in dnat.into_iter().chain(other) {
+            let origin = rule.origin.clone();
+            match import.translate(rule) {
+                Ok(()) => import.translated += 1,
+                Err(reason) => import.untranslated.push((origin, reason)),
+            }
+        }
+        Ok(import)
+    }
+
+    fn translate(&mut self, rule: ForeignRule) -> Result<(), String> {
+        if !rule.unsupported.is_empty() {
+            return Err(format!("matches on {}", rule.unsupported.join(", ")));
+        }
+        match &rule.interface {
+            Some(interface) if *interface != self.iface => {
+                return Err(format!("only for packets from {}", interface))
+            }
+            _ => {}
+        }
+        match &rule.out_interface {
+            Some(interface) if *interface != self.iface => {
+                return Err(format!("only for packets to {}", interface))
+            }
+            _ => {}
+        }
+        let comment = format!("# {}\n", rule.origin);
+        let verdict = rule.verdict.clone().ok_or("no verdict")?;
+        match (verdict, rule.hook) {
+            (Verdict::Accept | Verdict::Drop | Verdict::Reject, _) if rule.nat => {
+                Err("verdict in a nat chain".to_string())
+            }
+            (
+                verdict @ (Verdict::Accept | Verdict::Drop | Verdict::Reject),
+                Some(Hook::Input | Hook::Forward),
+            ) => {
+                let action = if verdict == Verdict::Accept {
+                    "pass"
+                } else {
+                    "drop"
+                };
+                if rule.protocol.as_deref() == Some("icmp") {
+                    return self.translate_icmp(&rule, action, comment);
+                }
+                if rule.destination.is_some() || rule.protocol.is_some() || rule.port.is_some() {
+                    return Err("destination and protocol matches are only for sources".to_string());
+                }
+                let source = rule.source.ok_or("no source")?;
+                let pass = verdict == Verdict::Accept;
+                if let Some((_, _, origin)) = self
+                    .sources
+                    .iter()
+                    .find(|(earlier, _, _)| contains(*earlier, source))
+                {
+                    return Err(format!("shadowed by the rule on {}", short(origin)));
+                }
+                let mut entry = comment;
+                if verdict == Verdict::Reject {
+                    entry += "# Rejected in the source, XDP can only drop\n";
+                }
+                match host(Some(source)) {
+                    Some(address) => {
+                        let _ = write!(
+                            entry,
+                            "[[rule]]\nsource = \"{}\"\naction = \"{}\"\n",
+                            address, action
+                        );
+                        self.rules.push(entry);
+                    }
+                    None if pass => {
+                        return Err("prefix sources only drop, through a blocklist".to_string())
+                    }
+                    None => {
+                        // Blocklists are checked before the rules, which would drop
+                        // the sources an earlier rule lets in
+                        let passed = self
+                            .sources
+                            .iter()
+                            .find(|(earlier, pass, _)| *pass && contains(source, *earlier));
+                        if let Some(((address, _), _, origin)) = passed {
+                            return Err(format!(
+                                "prefix holds {}, which the rule on {} passes",
+                                Ipv4Addr::from(*address),
+                                short(origin)
+                            ));
+                        }
+                        let (network, len) = source;
+                        let _ = writeln!(entry, "{}/{}", Ipv4Addr::from(network), len);
+                        self.blocklist.push(entry);
+                    }
+                }
+                self.sources.push((source, pass, rule.origin.clone()));
+                Ok(())
+            }
+            (Verdict::Dnat(private, to_port), Some(Hook::Prerouting)) => {
+                let public = host(rule.destination).ok_or("DNAT without a single destination")?;
+                if rule.source.is_some() || rule.source_port.is_some() {
+                    return Err("DNAT of some sources only".to_string());
+                }
+                let mut entry = comment;
+                let _ = write!(
+                    entry,
+                    "[[nat]]\npublic = \"{}\"\nprivate = \"{}\"\n",
+                    public, private
+                );
+                match (rule.protocol.as_deref(), rule.port) {
+                    (Some(protocol @ ("tcp" | "udp")), Some(port)) => {
+                        let _ = write!(entry, "protocol = \"{}\"\nport = {}\n", protocol, port);
+                        if let Some(to_port) = to_port.filter(|to_port| *to_port != port) {
+                            let _ = writeln!(entry, "to_port = {}", to_port);
+                        }
+                    }
+                    (None, None) if to_port.is_none() => {}
+                    _ => return Err("DNAT of a protocol without a port".to_string()),
+                }
+                self.nat.push(entry);
+                self.nat_pairs.push((private, public));
+                Ok(())
+            }
+            (Verdict::Snat(public), Some(Hook::Postrouting)) => {
+                let private = host(rule.source).ok_or("SNAT without a single source")?;
+                if self.nat_pairs.contains(&(private, public)) {
+                    Ok(())
+                } else {
+                    Err("SNAT without the DNAT of the same addresses".to_string())
+                }
+            }
+            (Verdict::Other(target), _) if !target.is_empty() => Err(format!("target {}", target)),
+            _ => Err("verdict outside of the chains it is translated from".to_string()),
+        }
+    }
+
+    fn translate_icmp(
+        &mut self,
+        rule: &ForeignRule,
+        action: &str,
+        comment: String,
+    ) -> Result<(), String> {
+        if rule.source.is_some() || rule.destination.is_some() {
+            return Err("ICMP policy of some addresses only".to_string());
+        }
+        let icmp_type = rule.icmp_type.as_deref().ok_or("ICMP without a type")?;
+        // iptables writes type/code
+        let (icmp_type, code) = match icmp_type.split_once('/') {
+            Some((icmp_type, code)) => (
+                icmp_type,
+                Some(code.parse::<u8>().map_err(|_| "ICMP code")?),
+            ),
+            None => (icmp_type, None),
+        };
+        let number = parse_icmp_type(icmp_type)?;
+        let earlier = self.icmp_types.iter().find(|(earlier, earlier_code, _)| {
+            *earlier == number && (earlier_code.is_none() || *earlier_code == code)
+        });
+        if let Some((_, _, origin)) = earlier {
+            return Err(format!("shadowed by the rule on {}", short(origin)));
+        }
+        self.icmp_types.push((number, code, rule.origin.clone()));
+        let mut entry = comment;
+        match icmp_type_name(number) {
+            Some(name) => {
+                let _ = writeln!(entry, "[[icmp]]\ntype = \"{}\"", name);
+            }
+            None => {
+                let _ = writeln!(entry, "[[icmp]]\ntype = {}", number);
+            }
+        }
+        if let Some(code) = code {
+            let _ = writeln!(entry, "code = {}", code);
+        }
+        let _ = writeln!(entry, "action = \"{}\"", action);
+        self.icmp.push(entry);
+        Ok(())
+    }
+
+    // The translated rules as a config file, with prefix sources in a blocklist
+    // read from the given path
+    pub fn to_toml(&self, blocklist: &Path) -> String {
+        let list = if self.blocklist.is_empty() {
+            None
+        } else {
+            Some(format!(
+                "[[blocklist]]\nname = \"imported\"\npath = \"{}\"\n",
+                blocklist.display()
+            ))
+        };
+        let entries: Vec<&str> = self
+            .rules
+            .iter()
+            .chain(&list)
+            .chain(&self.icmp)
+            .chain(&self.nat)
+            .map(String::as_str)
+            .collect();
+        entries.join("\n")
+    }
+
+    // The list file of the prefix sources, with the rule of each in a comment
+    pub fn blocklist(&self) -> String {
+        self.blocklist.concat()
+    }
+}
+
+// Prints the config translated from a ruleset and reports what was left out. The
+// prefix sources are written next to the ruleset, as a list with .blocklist
+// added to its name
+pub fn import(path: &Path, iface: &str) -> Result<(), anyhow::Error> {
+    let import = Import::from_file(path, iface)?;
+    let mut blocklist = fs::canonicalize(path)?.into_os_string();
+    blocklist.push(".blocklist");
+    let blocklist = PathBuf::from(blocklist);
+    if !import.blocklist.is_empty() {
+        fs::write(&blocklist, import.blocklist())
+            .with_context(|| format!("failed to write {}", blocklist.display()))?;
+        eprintln!(
+            "Wrote {} prefix sources to {}",
+            import.blocklist.len(),
+            blocklist.display()
+        );
+    }
+    print!("{}", import.to_toml(&blocklist));
+    eprintln!(
+        "Translated {} rules, {} could not be",
+        import.translated,
+        import.untranslated.len()
+    );
+    for (origin, reason) in &import.untranslated {
+        eprintln!("  {}: {}", origin, reason);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IPTABLES_SAVE: &str = "\
+# Generated by iptables-save v1.8.7 on Mon Oct 14 10:12:31 2024
+*nat
+:PREROUTING ACCEPT [12:720]
+:INPUT ACCEPT [0:0]
+:OUTPUT ACCEPT [3:228]
+:POSTROUTING ACCEPT [3:228]
+-A PREROUTING -d 203.0.113.6/32 -i eth0 -p tcp -m tcp --dport 8080 -j DNAT --to-destination 10.0.0.6:80
+-A POSTROUTING -s 10.0.0.6/32 -o eth0 -j SNAT --to-source 203.0.113.6
+COMMIT
+# Completed on Mon Oct 14 10:12:31 2024
+# Generated by iptables-save v1.8.7 on Mon Oct 14 10:12:31 2024
+*filter
+:INPUT ACCEPT [1045:81233]
+:FORWARD DROP [0:0]
+:OUTPUT ACCEPT [998:120731]
+-A INPUT -s 198.51.100.7/32 -j DROP
+-A INPUT -s 198.51.100.7/32 -j ACCEPT
+-A INPUT -s 192.0.2.10/32 -i eth0 -j ACCEPT
+-A INPUT -s 192.0.2.11/32 -i docker0 -j DROP
+-A INPUT -s 203.0.113.0/24 -m comment --comment \"abuse reports\" -j DROP
+-A INPUT -s 203.0.113.9/32 -j ACCEPT
+-A INPUT -s 10.0.0.0/8 -j REJECT --reject-with icmp-port-unreachable
+-A INPUT -p icmp -m icmp --icmp-type 8 -j DROP
+-A INPUT -p icmp -m icmp --icmp-type 8 -j ACCEPT
+-A INPUT -p tcp -m tcp --dport 22 -m conntrack --ctstate NEW -j ACCEPT
+-A FORWARD -s 192.0.2.0/24 -j ACCEPT
+COMMIT
+# Completed on Mon Oct 14 10:12:31 2024
+";
+
+    const NFT_JSON: &str = r#"{"nftables": [
+{"metainfo": {"version": "1.0.2", "release_name": "Lester Gooch", "json_schema_version": 1}},
+{"table": {"family": "inet", "name": "filter", "handle": 1}},
+{"chain": {"family": "inet", "table": "filter", "name": "input", "handle": 1, "type": "filter", "hook": "input", "prio": 0, "policy": "accept"}},
+{"rule": {"family": "inet", "table": "filter", "chain": "input", "handle": 4, "expr": [{"match": {"op": "==", "left": {"payload": {"protocol": "ip", "field": "saddr"}}, "right": "198.51.100.7"}}, {"counter": {"packets": 0, "bytes": 0}}, {"drop": null}]}},
+{"rule": {"family": "inet", "table": "filter", "chain": "input", "handle": 5, "expr": [{"match": {"op": "==", "left": {"payload": {"protocol": "ip", "field": "saddr"}}, "right": "198.51.100.7"}}, {"accept": null}]}},
+{"rule": {"family": "inet", "table": "filter", "chain": "input", "handle": 6, "expr": [{"match": {"op": "==", "left": {"meta": {"key": "iifname"}}, "right": "wg0"}}, {"match": {"op": "==", "left": {"payload": {"protocol": "ip", "field": "saddr"}}, "right": "192.0.2.11"}}, {"drop": null}]}},
+{"rule": {"family": "inet", "table": "filter", "chain": "input", "handle": 7, "expr": [{"match": {"op": "==", "left": {"meta": {"key": "iifname"}}, "right": "eth0"}}, {"match": {"op": "==", "left": {"payload": {"protocol": "ip", "field": "saddr"}}, "right": {"prefix": {"addr": "203.0.113.0", "len": 24}}}}, {"counter": {"packets": 12, "bytes": 840}}, {"drop": null}]}},
+{"rule": {"family": "inet", "table": "filter", "chain": "input", "handle": 8, "expr": [{"match": {"op": "==", "left": {"payload": {"protocol": "icmp", "field": "type"}}, "right": "echo-request"}}, {"drop": null}]}},
+{"rule": {"family": "inet", "table": "filter", "chain": "input", "handle": 9, "expr": [{"match": {"op": "==", "left": {"payload": {"protocol": "tcp", "field": "dport"}}, "right": 22}}, {"match": {"op": "in", "left": {"ct": {"key": "state"}}, "right": "new"}}, {"accept": null}]}},
+{"table": {"family": "ip", "name": "nat", "handle": 2}},
+{"chain": {"family": "ip", "table": "nat", "name": "prerouting", "handle": 1, "type": "nat", "hook": "prerouting", "prio": -100, "policy": "accept"}},
+{"rule": {"family": "ip", "table": "nat", "chain": "prerouting", "handle": 3, "expr": [{"match": {"op": "==", "left": {"payload": {"protocol": "ip", "field": "daddr"}}, "right": "203.0.113.6"}}, {"match": {"op": "==", "left": {"payload": {"protocol": "tcp", "field": "dport"}}, "right": 8080}}, {"dnat": {"addr": "10.0.0.6", "port": 80}}]}}
+]}"#;
+
+    fn reason<'a>(import: &'a Import, origin: &str) -> Option<&'a str> {
+        import
+            .untranslated
+            .iter()
+            .find(|(untranslated, _)| short(untranslated) == origin)
+            .map(|(_, reason)| reason.as_str())
+    }
+
+    #[test]
+    fn iptables_first_rule_for_a_source_wins() {
+        let import = Import::from_text(IPTABLES_SAVE, "eth0").unwrap();
+        let toml = import.to_toml(Path::new("/tmp/rules.v4.blocklist"));
+        assert!(toml.contains("source = \"198.51.100.7\"\naction = \"drop\""));
+        assert!(!toml.contains("source = \"198.51.100.7\"\naction = \"pass\""));
+        assert_eq!(
+            reason(&import, "line 17"),
+            Some("shadowed by the rule on line 16")
+        );
+        assert_eq!(
+            reason(&import, "line 21"),
+            Some("shadowed by the rule on line 20")
+        );
+        assert_eq!(
+            reason(&import, "line 24"),
+            Some("shadowed by the rule on line 23")
+        );
+    }
+
+    #[test]
+    fn iptables_interfaces() {
+        let import = Import::from_text(IPTABLES_SAVE, "eth0").unwrap();
+        let toml = import.to_toml(Path::new("/tmp/rules.v4.blocklist"));
+        assert!(toml.contains("source = \"192.0.2.10\"\naction = \"pass\""));
+        assert!(!toml.contains("192.0.2.11"));
+        assert_eq!(
+            reason(&import, "line 19"),
+            Some("only for packets from docker0")
+        );
+        // The DNAT on eth0 and its SNAT back out of it
+        assert!(toml.contains(
+            "[[nat]]\npublic = \"203.0.113.6\"\nprivate = \"10.0.0.6\"\nprotocol = \"tcp\"\nport = 8080\nto_port = 80\n"
+        ));
+        assert_eq!(reason(&import, "line 7"), None);
+        assert_eq!(reason(&import, "line 8"), None);
+
+        let import = Import::from_text(IPTABLES_SAVE, "eth1").unwrap();
+        assert_eq!(
+            reason(&import, "line 7"),
+            Some("only for packets from eth0")
+        );
+        assert_eq!(reason(&import, "line 8"), Some("only for packets to eth0"));
+        assert_eq!(
+            reason(&import, "line 18"),
+            Some("only for packets from eth0")
+        );
+    }
+
+    #[test]
+    fn iptables_prefix_sources_go_to_a_blocklist() {
+        let import = Import::from_text(IPTABLES_SAVE, "eth0").unwrap();
+        let toml = import.to_toml(Path::new("/tmp/rules.v4.blocklist"));
+        assert!(toml
+            .contains("[[blocklist]]\nname = \"imported\"\npath = \"/tmp/rules.v4.blocklist\"\n"));
+        let list = import.blocklist();
+        assert!(list.contains("\n203.0.113.0/24\n"));
+        assert!(list.contains("Rejected in the source"));
+        assert!(list.contains("\n10.0.0.0/8\n"));
+        let (prefixes, skipped) =
+            crate::blocklist::parse_list(&list, crate::blocklist::ListFormat::List);
+        assert_eq!(prefixes.len(), 2);
+        assert_eq!(skipped, 0);
+        // Inside the dropped prefix, so the later ACCEPT never applied
+        assert_eq!(
+            reason(&import, "line 21"),
+            Some("shadowed by the rule on line 20")
+        );
+        assert_eq!(
+            reason(&import, "line 26"),
+            Some("prefix sources only drop, through a blocklist")
+        );
+        assert_eq!(
+            reason(&import, "line 14"),
+            Some("default DROP policy, sources without a rule pass")
+        );
+    }
+
+    #[test]
+    fn prefix_drop_after_a_pass_for_a_host_in_it() {
+        let text = "*filter\n-A INPUT -s 192.0.2.1/32 -j ACCEPT\n-A INPUT -s 192.0.2.0/24 -j DROP\nCOMMIT\n";
+        let import = Import::from_text(text, "eth0").unwrap();
+        assert!(import.blocklist().is_empty());
+        assert_eq!(
+            reason(&import, "line 3"),
+            Some("prefix holds 192.0.2.1, which the rule on line 2 passes")
+        );
+    }
+
+    #[test]
+    fn nft_json() {
+        let import = Import::from_text(NFT_JSON, "eth0").unwrap();
+        let toml = import.to_toml(Path::new("/tmp/ruleset.json.blocklist"));
+        assert!(toml.contains("source = \"198.51.100.7\"\naction = \"drop\""));
+        assert!(!toml.contains("action = \"pass\""));
+        assert_eq!(
+            reason(&import, "inet filter input handle 5"),
+            Some("shadowed by the rule on inet filter input handle 4")
+        );
+        assert_eq!(
+            reason(&import, "inet filter input handle 6"),
+            Some("only for packets from wg0")
+        );
+        assert!(import.blocklist().contains("\n203.0.113.0/24\n"));
+        assert!(toml.contains("[[icmp]]\ntype = \"echo-request\"\naction = \"drop\"\n"));
+        assert!(reason(&import, "inet filter input handle 9").is_some());
+        assert!(toml.contains(
+            "[[nat]]\npublic = \"203.0.113.6\"\nprivate = \"10.0.0.6\"\nprotocol = \"tcp\"\nport = 8080\nto_port = 80\n"
+        ));
+        assert_eq!(import.translated, 4);
+    }
+
+    #[test]
+    fn prefixes_contain() {
+        let prefix = |s| parse_prefix(s).unwrap();
+        assert!(contains(prefix("10.0.0.0/8"), prefix("10.1.2.3")));
+        assert!(contains(prefix("0.0.0.0/0"), prefix("192.0.2.0/24")));
+        assert!(contains(prefix("10.0.0.0/8"), prefix("10.0.0.0/8")));
+        assert!(!contains(prefix("10.0.0.0/32"), prefix("10.0.0.0/8")));
+        assert!(!contains(prefix("10.0.0.0/8"), prefix("11.0.0.0/8")));
+    }
+}
@@ -0,0 +1,106 @@
+use anyhow::bail;
+use aya::maps::Array;
+use aya::Bpf;
+use ebpfapp_common::{KnockGate, CONFIG_KNOCK_GATES, KNOCK_GATES, KNOCK_STEPS};
+use log::info;
+use serde::Deserialize;
+use std::convert::TryFrom;
+
+use crate::lb::Protocol;
+
+fn default_protocol() -> Protocol {
+    Protocol::Tcp
+}
+
+fn default_timeout() -> u64 {
+    5
+}
+
+fn default_open() -> u64 {
+    30
+}
+
+// A port knocking gate in front of a port, e.g.
+//
+// [[knock]]
+// name = "ssh"
+// sequence = [7000, 8000, 9000]
+// knock_protocol = "udp"
+// port = 22
+// timeout = 5
+// open = 30
+#[derive(Debug, Clone, Deserialize)]
+pub struct KnockConfig {
+    pub name: String,
+    // Ports to knock on in order
+    pub sequence: Vec<u16>,
+    #[serde(default = "default_protocol")]
+    pub knock_protocol: Protocol,
+    // The protected port
+    pub port: u16,
+    #[serde(default = "default_protocol")]
+    pub protocol: Protocol,
+    // Seconds allowed between two knocks
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    // Seconds the port stays open for the source after the last knock, or after
+    // its last packet to the port
+    #[serde(default = "default_open")]
+    pub open: u64,
+}
+
+impl KnockConfig {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.sequence.is_empty() || self.sequence.len() > KNOCK_STEPS {
+            bail!("a sequence has 1 to {} ports", KNOCK_STEPS);
+        }
+        if self.sequence.contains(&0) {
+            bail!("port 0 can't be knocked on");
+        }
+        if self.knock_protocol == self.protocol && self.sequence.contains(&self.port) {
+            bail!("the protected port {} is part of the sequence", self.port);
+        }
+        if self.timeout == 0 || self.open == 0 {
+            bail!("timeout and open must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+pub fn load_knocks(bpf: &Bpf, knocks: &[KnockConfig]) -> Result<(), anyhow::Error> {
+    if knocks.len() > KNOCK_GATES as usize {
+        bail!(
+            "{} knock gates configured, at most {} are supported",
+            knocks.len(),
+            KNOCK_GATES
+        );
+    }
+    let mut gates: Array<_, KnockGate> = Array::try_from(bpf.map_mut("KNOCK_GATES")?)?;
+    for (index, knock) in knocks.iter().enumerate() {
+        let mut ports = [0; KNOCK_STEPS];
+        ports[..knock.sequence.len()].copy_from_slice(&knock.sequence);
+        let gate = KnockGate {
+            ports,
+            steps: knock.sequence.len() as u32,
+            knock_protocol: knock.knock_protocol.number(),
+            protocol: knock.protocol.number(),
+            port: knock.port,
+            timeout: knock.timeout * 1_000_000_000,
+            open: knock.open * 1_000_000_000,
+        };
+        gates.set(index as u32, gate, 0)?;
+        info!(
+            "Knock gate {}: {} opens {}/{} for {}s after {:?}/{}",
+            index + 1,
+            knock.name,
+            knock.port,
+            knock.protocol.name(),
+            knock.open,
+            knock.sequence,
+            knock.knock_protocol.name()
+        );
+    }
+    let mut config: Array<_, u32> = Array::try_from(bpf.map_mut("CONFIG")?)?;
+    config.set(CONFIG_KNOCK_GATES, knocks.len() as u32, 0)?;
+    Ok(())
+}
@@ -0,0 +1,410 @@
+use anyhow::{anyhow, bail, Context};
+use aya::maps::{Array, HashMap, MapRefMut};
+use aya::Bpf;
+use ebpfapp_common::{
+    Backend, ForwardMode, Service, ServiceKey, BACKEND_TX, CONFIG_LB_SOURCE, LB_BACKENDS,
+    LB_SERVICES, MAGLEV_SIZE,
+};
+use log::{info, warn};
+use nix::ifaddrs::getifaddrs;
+use nix::sys::socket::SockAddr;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+
+use crate::bogons::interface_addresses;
+use crate::config::Config;
+use crate::parser::ParserToString;
+use crate::redirect::{Redirect, RedirectPorts};
+use crate::Command;
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    pub fn number(self) -> u8 {
+        match self {
+            Protocol::Tcp => IPPROTO_TCP,
+            Protocol::Udp => IPPROTO_UDP,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Forward {
+    Mac,
+    Ipip,
+}
+
+// A backend of a service. The MAC of the next hop is looked up in the neighbour
+// table when it isn't given, so the backend (or its gateway) should be in it
+#[derive(Debug, Clone, Deserialize)]
+pub struct BackendConfig {
+    pub address: Ipv4Addr,
+    #[serde(default, deserialize_with = "mac")]
+    pub mac: Option<[u8; 6]>,
+    // Reached through another interface, by XDP_REDIRECT
+    pub interface: Option<String>,
+}
+
+// A load balanced service, e.g.
+//
+// [[service]]
+// vip = "192.0.2.10"
+// port = 80
+// protocol = "tcp"
+// forward = "ipip"
+//
+// [[service.backend]]
+// address = "10.0.1.1"
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServiceConfig {
+    pub vip: Ipv4Addr,
+    pub port: u16,
+    pub protocol: Protocol,
+    pub forward: Forward,
+    #[serde(rename = "backend")]
+    pub backends: Vec<BackendConfig>,
+}
+
+impl ServiceConfig {
+    // What tells services apart, a service with the same key replaces the backends
+    pub fn key(&self) -> (Ipv4Addr, u16, Protocol) {
+        (self.vip, self.port, self.protocol)
+    }
+}
+
+pub fn parse_mac(s: &str) -> Result<[u8; 6], String> {
+    let mut mac = [0; 6];
+    let mut parts = s.split(':');
+    for byte in mac.iter_mut() {
+        let part = parts.next().ok_or_else(|| format!("invalid MAC {}", s))?;
+        *byte = u8::from_str_radix(part, 16).map_err(|_| format!("invalid MAC {}", s))?;
+    }
+    if parts.next().is_some() {
+        return Err(format!("invalid MAC {}", s));
+    }
+    Ok(mac)
+}
+
+fn mac<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<[u8; 6]>, D::Error> {
+    let mac = String::deserialize(deserializer)?;
+    parse_mac(&mac).map(Some).map_err(serde::de::Error::custom)
+}
+
+// Hardware address of an entry in the kernel's ARP table
+fn neighbour_mac(address: Ipv4Addr) -> Result<Option<[u8; 6]>, anyhow::Error> {
+    let table = fs::read_to_string("/proc/net/arp")?;
+    for line in table.lines().skip(1) {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.len() >= 4 && fields[0].parse() == Ok(address) {
+            return Ok(parse_mac(fields[3]).ok());
+        }
+    }
+    Ok(None)
+}
+
+fn interface_mac(iface: &str) -> Result<[u8; 6], anyhow::Error> {
+    getifaddrs()?
+        .filter(|ifaddr| ifaddr.interface_name == iface)
+        .find_map(|ifaddr| match ifaddr.address {
+            Some(SockAddr::Link(link)) => Some(link.addr()),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("no MAC address on {}", iface))
+}
+
+// FNV-1a, stable across runs and builds so every balancer fills the table the same way
+fn fnv1a(data: &[u8], basis: u64) -> u64 {
+    data.iter().fold(basis, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// Maglev lookup table: each backend walks its own permutation of the rows and
+// they take turns claiming the next free row on it
+fn maglev_table(backends: &[Ipv4Addr]) -> Vec<u32> {
+    let size = MAGLEV_SIZE as u64;
+    let permutations: Vec<(u64, u64)> = backends
+        .iter()
+        .map(|address| {
+            let name = address.octets();
+            let offset = fnv1a(&name, 0xcbf2_9ce4_8422_2325) % size;
+            let skip = fnv1a(&name, 0x8422_2325_cbf2_9ce4) % (size - 1) + 1;
+            (offset, skip)
+        })
+        .collect();
+
+    let mut table = vec![u32::MAX; MAGLEV_SIZE as usize];
+    let mut next = vec![0u64; backends.len()];
+    let mut filled = 0;
+    while filled < table.len() {
+        for (backend, &(offset, skip)) in permutations.iter().enumerate() {
+            let mut row = (offset + next[backend] * skip) % size;
+            while table[row as usize] != u32::MAX {
+                next[backend] += 1;
+                row = (offset + next[backend] * skip) % size;
+            }
+            table[row as usize] = backend as u32;
+            next[backend] += 1;
+            filled += 1;
+            if filled == table.len() {
+                break;
+            }
+        }
+    }
+    table
+}
+
+// Installs and removes services in the SERVICES, BACKENDS and MAGLEV maps
+pub struct Balancer {
+    iface: String,
+    services: HashMap<MapRefMut, ServiceKey, Service>,
+    backends: Array<MapRefMut, Backend>,
+    maglev: Array<MapRefMut, u32>,
+    ids: BTreeMap<(Ipv4Addr, u16, Protocol), u32>,
+}
+
+impl Balancer {
+    pub fn new(bpf: &Bpf, iface: &str) -> Result<Self, anyhow::Error> {
+        // IPIP packets come from the interface's first address
+        if let Some(source) = interface_addresses(iface)?.first() {
+            let mut config: Array<_, u32> = Array::try_from(bpf.map_mut("CONFIG")?)?;
+            config.set(CONFIG_LB_SOURCE, u32::from(*source), 0)?;
+        }
+        Ok(Balancer {
+            iface: iface.to_string(),
+            services: HashMap::try_from(bpf.map_mut("SERVICES")?)?,
+            backends: Array::try_from(bpf.map_mut("BACKENDS")?)?,
+            maglev: Array::try_from(bpf.map_mut("MAGLEV")?)?,
+            ids: BTreeMap::new(),
+        })
+    }
+
+    fn backend(
+        &self,
+        config: &BackendConfig,
+        ports: &mut RedirectPorts,
+    ) -> Result<Backend, anyhow::Error> {
+        let mac = match config.mac {
+            Some(mac) => mac,
+            None => neighbour_mac(config.address)?
+                .with_context(|| format!("no neighbour entry for backend {}", config.address))?,
+        };
+        let (port, source_mac) = match &config.interface {
+            Some(iface) => (
+                ports.port(&Redirect::Interface(iface.clone()))?,
+                interface_mac(iface)?,
+            ),
+            None => (BACKEND_TX, interface_mac(&self.iface)?),
+        };
+        Ok(Backend {
+            address: u32::from(config.address),
+            port,
+            mac,
+            source_mac,
+        })
+    }
+
+    // Adds the service, or replaces the backends of an existing one. Returns the service id
+    pub fn apply(
+        &mut self,
+        config: &ServiceConfig,
+        ports: &mut RedirectPorts,
+    ) -> Result<u32, anyhow::Error> {
+        if config.backends.is_empty() || config.backends.len() > LB_BACKENDS as usize {
+            bail!("a service needs 1 to {} backends", LB_BACKENDS);
+        }
+        let name = config.key();
+        let id = match self.ids.get(&name) {
+            Some(id) => *id,
+            None => (0..LB_SERVICES)
+                .find(|id| !self.ids.values().any(|used| used == id))
+                .ok_or_else(|| anyhow!("no service slot left"))?,
+        };
+
+        let backends = config
+            .backends
+            .iter()
+            .map(|backend| self.backend(backend, ports))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (slot, backend) in backends.iter().enumerate() {
+            self.backends
+                .set(id * LB_BACKENDS + slot as u32, *backend, 0)?;
+        }
+        let addresses: Vec<_> = config.backends.iter().map(|b| b.address).collect();
+        for (row, slot) in maglev_table(&addresses).into_iter().enumerate() {
+            self.maglev.set(id * MAGLEV_SIZE + row as u32, slot, 0)?;
+        }
+
+        let key = ServiceKey {
+            vip: u32::from(config.vip),
+            port: config.port,
+            protocol: config.protocol.number(),
+            _pad: 0,
+        };
+        let forward = match config.forward {
+            Forward::Mac => ForwardMode::MAC,
+            Forward::Ipip => ForwardMode::IPIP,
+        };
+        self.services.insert(key, Service { id, forward }, 0)?;
+        self.ids.insert(name, id);
+        info!(
+            "Service {}: {}:{} {:?} {} to {:?}",
+            id,
+            config.vip,
+            config.port,
+            config.protocol,
+            forward.to_str(),
+            addresses
+        );
+        Ok(id)
+    }
+
+    pub fn remove(
+        &mut self,
+        vip: Ipv4Addr,
+        port: u16,
+        protocol: Protocol,
+    ) -> Result<(), anyhow::Error> {
+        let id = self
+            .ids
+            .remove(&(vip, port, protocol))
+            .ok_or_else(|| anyhow!("no service {}:{}", vip, port))?;
+        let key = ServiceKey {
+            vip: u32::from(vip),
+            port,
+            protocol: protocol.number(),
+            _pad: 0,
+        };
+        self.services.remove(&key)?;
+        info!("Service {} removed: {}:{} {:?}", id, vip, port, protocol);
+        Ok(())
+    }
+}
+
+// Reloads the services of the config on SIGHUP. Services in the file are applied,
+// which replaces the backends of existing ones, and services no longer in it are
+// removed. The other sections of the config are only read at startup
+pub fn spawn_service_reload(
+    path: PathBuf,
+    mut services: Vec<ServiceConfig>,
+    tx: mpsc::Sender<Command>,
+) -> Result<(), anyhow::Error> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            let config = match Config::load(&path) {
+                Ok(config) => config,
+                Err(e) => {
+                    warn!("Services not reloaded: {:#}", e);
+                    continue;
+                }
+            };
+            info!(
+                "Reloading {} services from {}",
+                config.services.len(),
+                path.display()
+            );
+            let removed = services
+                .iter()
+                .filter(|old| {
+                    !config
+                        .services
+                        .iter()
+                        .any(|service| service.key() == old.key())
+                })
+                .map(|old| Command::RemoveService {
+                    vip: old.vip,
+                    port: old.port,
+                    protocol: old.protocol,
+                });
+            let commands: Vec<_> = removed
+                .chain(config.services.iter().cloned().map(Command::Service))
+                .collect();
+            for command in commands {
+                if tx.send(command).await.is_err() {
+                    return;
+                }
+            }
+            services = config.services;
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backends(count: u8) -> Vec<Ipv4Addr> {
+        (1..=count).map(|i| Ipv4Addr::new(10, 0, 1, i)).collect()
+    }
+
+    #[test]
+    fn maglev_table_is_filled_evenly() {
+        for count in [1, 2, 3, 7, LB_BACKENDS as u8] {
+            let table = maglev_table(&backends(count));
+            assert_eq!(table.len(), MAGLEV_SIZE as usize);
+            let mut rows = vec![0; count as usize];
+            for slot in &table {
+                assert!(*slot < count as u32, "row left empty or out of range");
+                rows[*slot as usize] += 1;
+            }
+            // Backends take turns, so their shares differ by at most a row
+            let (min, max) = (rows.iter().min().unwrap(), rows.iter().max().unwrap());
+            assert!(max - min <= 1, "{} backends got {:?} rows", count, rows);
+        }
+    }
+
+    #[test]
+    fn maglev_table_is_stable() {
+        assert_eq!(maglev_table(&backends(5)), maglev_table(&backends(5)));
+    }
+
+    #[test]
+    fn maglev_removal_moves_few_other_rows() {
+        let before = backends(10);
+        let mut after = before.clone();
+        let removed = after.remove(3);
+        let old = maglev_table(&before);
+        let new = maglev_table(&after);
+
+        let mut moved = 0;
+        for (old, new) in old.iter().zip(new.iter()) {
+            let (old, new) = (before[*old as usize], after[*new as usize]);
+            if old == removed {
+                assert_ne!(new, removed);
+            } else if old != new {
+                moved += 1;
+            }
+        }
+        // The rows of the removed backend are spread over the others, the rest
+        // mostly keep their backend
+        assert!(
+            moved < MAGLEV_SIZE as usize / 20,
+            "{} rows of other backends moved",
+            moved
+        );
+    }
+}
@@ -1,17 +1,57 @@
+mod aggregate;
+mod blocklist;
+mod bogons;
+mod checks;
+mod config;
+mod detect;
+mod echo;
+mod export;
+mod flows;
+mod fragments;
+mod geoip;
+mod icmp;
+mod import;
+mod knock;
+mod lb;
+mod metrics;
+mod nat;
 mod parser;
+mod redirect;
+mod rules;
+mod sampling;
+mod schedule;
+mod sflow;
+mod sys;
+mod tunnels;
+mod watch;
+mod xsk;
 use anyhow::Context;
 use aya::maps::perf::AsyncPerfEventArray;
-use aya::maps::HashMap;
+use aya::maps::{HashMap, MapRefMut};
 use aya::programs::{Xdp, XdpFlags};
 use aya::util::online_cpus;
-use aya::{include_bytes_aligned, Bpf};
+use aya::{include_bytes_aligned, Bpf, BpfLoader};
 use bytes::BytesMut;
-use ebpfapp_common::{PacketType, XdpAction};
-use log::info;
+use config::Config;
+use ebpfapp_common::{
+    Check, FragmentPolicy, IcmpKey, PacketType, Reason, Rule, TunnelType, VlanKey, XdpAction,
+    ICMP_ANY_CODE,
+};
+use lb::{Balancer, Protocol, ServiceConfig};
+use log::{info, warn};
+use metrics::Metrics;
+use nat::{Nat, NatConfig};
 use parser::Packet;
+use redirect::{Redirect, RedirectPorts};
+use rules::RuleTable;
 use simplelog::{ColorChoice, ConfigBuilder, LevelFilter, TermLogger, TerminalMode};
+use std::collections::BTreeMap;
 use std::convert::{TryFrom, TryInto};
-use std::net::Ipv4Addr;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 use tokio::sync::mpsc;
 use tokio::{signal, task};
@@ -22,20 +62,184 @@ use crate::parser::{parse_buf, ParserToString};
 struct Opt {
     #[structopt(short, long, default_value = "eth0")]
     iface: String,
+    /// Path to a TOML file with the static rules
+    #[structopt(short, long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Drop packets with bogon or martian source addresses
+    #[structopt(long)]
+    bogons: bool,
+    /// The interface faces the internet, so RFC1918 sources are treated as bogons
+    #[structopt(long)]
+    wan: bool,
+    /// Serve prometheus metrics on this address, e.g. 0.0.0.0:9100
+    #[structopt(long)]
+    metrics_addr: Option<SocketAddr>,
+    /// Seconds between reloads of the blocklists in the config, 0 loads them once
+    #[structopt(long, default_value = "3600")]
+    blocklist_refresh: u64,
+    /// Header sanity checks to drop packets on: truncated, version, ihl, length, fragment, tcpflags, options
+    #[structopt(
+        long,
+        use_delimiter = true,
+        default_value = checks::DEFAULT_CHECKS,
+        parse(try_from_str = checks::parse_check)
+    )]
+    checks: Vec<Check>,
+    /// Fragment policies: drop, tiny, overlap. Fragments pass when none are given
+    #[structopt(long, use_delimiter = true, parse(try_from_str = fragments::parse_policy))]
+    fragments: Vec<FragmentPolicy>,
+    /// Apply rules to the inner addresses of these tunnels as well as the outer ones: ipip, gre, vxlan
+    #[structopt(long, use_delimiter = true, parse(try_from_str = tunnels::parse_tunnel))]
+    tunnels: Vec<TunnelType>,
+    /// Send an event for 1 in N packets of an action, e.g. pass=100,drop=10. pass=0 turns PASS events off
+    #[structopt(long, use_delimiter = true, parse(try_from_str = sampling::parse_sample))]
+    sample: Vec<sampling::SampleRate>,
+    /// Count packets per flow and action in the kernel and log a summary every N seconds.
+    /// Per packet events are then only sent for actions given a --sample rate
+    #[structopt(long)]
+    aggregate: Option<u64>,
+    /// Export flow records over UDP to this collector, e.g. 192.0.2.1:4739
+    #[structopt(long)]
+    flow_collector: Option<SocketAddr>,
+    /// Flow record format: ipfix or netflow9
+    #[structopt(long, default_value = "ipfix", parse(try_from_str = flows::parse_format))]
+    flow_format: flows::FlowFormat,
+    /// Seconds of traffic each flow record covers
+    #[structopt(long, default_value = "10")]
+    flow_interval: u64,
+    /// Send sFlow v5 samples to this collector, e.g. 192.0.2.1:6343
+    #[structopt(long)]
+    sflow_collector: Option<SocketAddr>,
+    /// Sample 1 in N packets for sFlow
+    #[structopt(long, default_value = "1000")]
+    sflow_rate: u32,
+    /// Seconds between sFlow counter samples
+    #[structopt(long, default_value = "20")]
+    sflow_counters: u64,
+    /// Open AF_XDP sockets that receive the packets of inspect rules
+    #[structopt(long)]
+    xsk: bool,
+    /// Receive queues to open AF_XDP sockets on with --xsk, every queue of the interface by default
+    #[structopt(long)]
+    xsk_queues: Option<u32>,
+    /// Hand inspected packets to the kernel stack through this TAP interface, created when
+    /// missing, instead of dropping them
+    #[structopt(long)]
+    xsk_reinject: Option<String>,
+    /// Answer pings to the interface's addresses from XDP instead of the kernel stack
+    #[structopt(long)]
+    echo: bool,
+    /// Echo replies sent a second with --echo, requests over the limit are dropped
+    #[structopt(long, default_value = "1000")]
+    echo_rate: u32,
+    /// Print the rules of an iptables-save dump or `nft -j list ruleset` output for
+    /// --iface as config and exit, the rules that can't be translated are listed on
+    /// stderr
+    #[structopt(long, parse(from_os_str))]
+    import: Option<PathBuf>,
+    /// Print the policy of the config as an nftables ruleset or an iptables-restore
+    /// file and exit, for hosts where the XDP program can't be attached
+    #[structopt(long, parse(try_from_str = export::parse_format))]
+    export: Option<export::ExportFormat>,
 }
 
 #[derive(Debug)]
 pub enum Command {
-    Block { ip: Ipv4Addr },
-    Allow { ip: Ipv4Addr },
+    // Drop the source, only for ttl when given
+    Block {
+        ip: Ipv4Addr,
+        vlan: Option<u16>,
+        ttl: Option<Duration>,
+    },
+    Allow {
+        ip: Ipv4Addr,
+        vlan: Option<u16>,
+    },
+    // Send the source's packets out of another interface
+    Redirect {
+        ip: Ipv4Addr,
+        vlan: Option<u16>,
+        interface: String,
+    },
+    // Deliver the source's packets to the AF_XDP consumer
+    Inspect {
+        ip: Ipv4Addr,
+        vlan: Option<u16>,
+    },
+    // A rule from the scheduler, in effect over the configured rule of the source
+    // until its window ends
+    Schedule(Box<Command>),
+    // Take out the scheduled rule of the source when its window ends
+    Unschedule {
+        ip: Ipv4Addr,
+        vlan: Option<u16>,
+    },
+    // Commands applied together, the rules for sources without a VLAN are swapped
+    // in at once
+    Batch(Vec<Command>),
+    // Add a load balanced service or replace its backends
+    Service(ServiceConfig),
+    // Take out a service that is no longer in the config, on SIGHUP
+    RemoveService {
+        vip: Ipv4Addr,
+        port: u16,
+        protocol: Protocol,
+    },
+    // Add a 1:1 NAT or port forward
+    Nat(NatConfig),
+    // A missing code applies the policy to every code of the type
+    IcmpBlock {
+        icmp_type: u8,
+        code: Option<u8>,
+    },
+    IcmpAllow {
+        icmp_type: u8,
+        code: Option<u8>,
+    },
 }
 
-fn process_bpf_events(bpf: &Bpf, tx: &mpsc::Sender<Command>) -> Result<(), anyhow::Error> {
+// The map entry a command installs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Target {
+    Source { ip: Ipv4Addr, vlan: Option<u16> },
+    Icmp { icmp_type: u8, code: Option<u8> },
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Source { ip, vlan: None } => write!(f, "{}", ip),
+            Target::Source {
+                ip,
+                vlan: Some(vlan_id),
+            } => write!(f, "{} on VLAN {}", ip, vlan_id),
+            Target::Icmp { icmp_type, code } => {
+                match icmp::icmp_type_name(*icmp_type) {
+                    Some(name) => write!(f, "ICMP {}", name)?,
+                    None => write!(f, "ICMP type {}", icmp_type)?,
+                }
+                match code {
+                    Some(code) => write!(f, " code {}", code),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+fn process_bpf_events(
+    bpf: &Bpf,
+    tx: &mpsc::Sender<Command>,
+    metrics: &Arc<Metrics>,
+    countries: Option<Arc<geoip::Countries>>,
+) -> Result<(), anyhow::Error> {
     // Load buffers from each cpu as AsyncPerfEventArray is a per cpu ring buffer.
     let mut perf_array = AsyncPerfEventArray::try_from(bpf.map_mut("EVENTS")?)?;
 
     for cpu_id in online_cpus()? {
         let tx = tx.clone();
+        let metrics = metrics.clone();
+        let countries = countries.clone();
         let mut per_cpu_buffer = perf_array.open(cpu_id, None)?;
         task::spawn(async move {
             let mut buffers = (0..10)
@@ -46,12 +250,17 @@ fn process_bpf_events(bpf: &Bpf, tx: &mpsc::Sender<Command>) -> Result<(), anyho
                 let events = per_cpu_buffer.read_events(&mut buffers).await.unwrap();
                 for buf in buffers.iter_mut().take(events.read) {
                     // let buf = &mut buffers[i];
-                    let packet = parse_and_log_packet(buf);
-                    // block icmp packets
-                    if packet.packet_type == PacketType::ICMP {
-                        let _ = tx.send(Command::Block { ip: packet.source }).await;
-                    } else {
-                        let _ = tx.send(Command::Allow { ip: packet.source }).await;
+                    let packet = parse_and_log_packet(buf, countries.as_deref());
+                    metrics.record(&packet);
+                    // Only sources no rule matched yet, so configured rules aren't overwritten
+                    if packet.reason != Reason::NONE {
+                        continue;
+                    }
+                    // ICMP is left to the [[icmp]] policy, a type without an entry
+                    // doesn't get its sender a rule either way
+                    if packet.packet_type != PacketType::ICMP {
+                        let ip = packet.source;
+                        let _ = tx.send(Command::Allow { ip, vlan: None }).await;
                     }
                 }
             }
@@ -60,41 +269,394 @@ fn process_bpf_events(bpf: &Bpf, tx: &mpsc::Sender<Command>) -> Result<(), anyho
     Ok(())
 }
 
-fn process_actions(bpf: &Bpf, mut rx: mpsc::Receiver<Command>) -> Result<(), anyhow::Error> {
-    // Load hash map
-    let mut action_list: HashMap<_, u32, u32> = HashMap::try_from(bpf.map_mut("ACTION_LIST")?)?;
+// The maps the entry of each target is written to
+struct Entries {
+    rule_table: RuleTable,
+    vlan_action_list: HashMap<MapRefMut, VlanKey, Rule>,
+    icmp_policy: HashMap<MapRefMut, IcmpKey, Rule>,
+}
+
+impl Entries {
+    fn insert(&mut self, target: Target, rule: Rule) -> Result<(), anyhow::Error> {
+        match target {
+            Target::Source {
+                ip,
+                vlan: Some(vlan_id),
+            } => {
+                let key = VlanKey {
+                    vlan_id: vlan_id as u32,
+                    source: u32::from(ip),
+                };
+                self.vlan_action_list.insert(key, rule, 0)?
+            }
+            Target::Source { ip, vlan: None } => self.rule_table.insert(ip, rule)?,
+            Target::Icmp { icmp_type, code } => {
+                let key = IcmpKey {
+                    icmp_type,
+                    code: code.unwrap_or(ICMP_ANY_CODE),
+                };
+                self.icmp_policy.insert(key, rule, 0)?
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, target: Target) -> Result<(), anyhow::Error> {
+        match target {
+            Target::Source {
+                ip,
+                vlan: Some(vlan_id),
+            } => self.vlan_action_list.remove(&VlanKey {
+                vlan_id: vlan_id as u32,
+                source: u32::from(ip),
+            })?,
+            Target::Source { ip, vlan: None } => self.rule_table.remove(ip)?,
+            Target::Icmp { icmp_type, code } => self.icmp_policy.remove(&IcmpKey {
+                icmp_type,
+                code: code.unwrap_or(ICMP_ANY_CODE),
+            })?,
+        }
+        Ok(())
+    }
+
+    // Writes the rule now in effect for the target, if it changed
+    fn update(
+        &mut self,
+        target: Target,
+        before: Option<Rule>,
+        after: Option<Rule>,
+    ) -> Result<(), anyhow::Error> {
+        match (before, after) {
+            (Some(before), Some(after)) if before.rule_id == after.rule_id => Ok(()),
+            (_, Some(after)) => self.insert(target, after),
+            (Some(_), None) => self.remove(target),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+// The rules given for a target. A block with a time limit is put over the rule
+// of a schedule, and that over the configured rule. Each is back in effect once
+// the one over it expires or its window ends
+#[derive(Clone, Default)]
+struct Layers {
+    block: Option<(Rule, Instant)>,
+    scheduled: Option<Rule>,
+    base: Option<Rule>,
+}
+
+impl Layers {
+    fn effective(&self) -> Option<Rule> {
+        self.block
+            .map(|(rule, _)| rule)
+            .or(self.scheduled)
+            .or(self.base)
+    }
+}
+
+fn same(rule: Option<Rule>, action: XdpAction, port: u32) -> bool {
+    matches!(rule, Some(rule) if rule.action == action && rule.port == port)
+}
+
+fn process_actions(
+    bpf: &Bpf,
+    iface: &str,
+    mut rx: mpsc::Receiver<Command>,
+) -> Result<(), anyhow::Error> {
+    let mut entries = Entries {
+        rule_table: RuleTable::new(bpf)?,
+        vlan_action_list: HashMap::try_from(bpf.map_mut("VLAN_ACTION_LIST")?)?,
+        icmp_policy: HashMap::try_from(bpf.map_mut("ICMP_POLICY")?)?,
+    };
+    let mut redirect_ports = RedirectPorts::new(bpf)?;
+    let mut balancer = Balancer::new(bpf, iface)?;
+    let mut nat = Nat::new(bpf)?;
     tokio::spawn(async move {
-        while let Some(cmd) = rx.recv().await {
-            let _droppable = match cmd {
-                Command::Block { ip } => {
-                    action_list.insert(u32::from(ip), XdpAction::DROP as u32, 0)
-                }
-                Command::Allow { ip } => {
-                    action_list.insert(u32::from(ip), XdpAction::PASS as u32, 0)
+        // Every entry gets its own id so events can be traced back to the command that created it
+        let mut next_rule_id = 1;
+        let mut layers: BTreeMap<Target, Layers> = BTreeMap::new();
+        let mut expiry = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            let cmd = tokio::select! {
+                cmd = rx.recv() => match cmd {
+                    Some(cmd) => cmd,
+                    None => break,
+                },
+                _ = expiry.tick() => {
+                    let now = Instant::now();
+                    let expired: Vec<Target> = layers
+                        .iter()
+                        .filter(|(_, rules)| matches!(rules.block, Some((_, at)) if at <= now))
+                        .map(|(target, _)| *target)
+                        .collect();
+                    for target in expired {
+                        let rules = layers.get_mut(&target).unwrap();
+                        let before = rules.effective();
+                        rules.block = None;
+                        let after = rules.effective();
+                        if after.is_none() {
+                            layers.remove(&target);
+                        }
+                        match (entries.update(target, before, after), after) {
+                            (Ok(()), Some(rule)) => info!(
+                                "Rule for {} expired, rule {} is back in effect",
+                                target, rule.rule_id
+                            ),
+                            (Ok(()), None) => info!("Rule for {} expired", target),
+                            (Err(e), _) => {
+                                warn!("Expired rule for {} not replaced: {:#}", target, e)
+                            }
+                        }
+                    }
+                    continue;
                 }
             };
+            // A batch replaces the rule table in one update instead of entry by entry
+            let (commands, mut batch) = match cmd {
+                Command::Batch(commands) => (commands, true),
+                cmd => (vec![cmd], false),
+            };
+            if batch {
+                if let Err(e) = entries.rule_table.stage() {
+                    warn!("Rule table not copied, applying the batch in place: {}", e);
+                    batch = false;
+                }
+            }
+            for cmd in commands {
+                let (cmd, scheduled) = match cmd {
+                    Command::Schedule(cmd) => (*cmd, true),
+                    cmd => (cmd, false),
+                };
+                let mut ttl = None;
+                let (target, action, redirect) = match cmd {
+                    Command::Batch(_) => {
+                        warn!("Nested batch ignored");
+                        continue;
+                    }
+                    Command::Nat(config) => {
+                        if let Err(e) = nat.apply(&config) {
+                            warn!("NAT {} not installed: {:#}", config, e);
+                        }
+                        continue;
+                    }
+                    Command::Service(service) => {
+                        if let Err(e) = balancer.apply(&service, &mut redirect_ports) {
+                            warn!(
+                                "Service {}:{} not installed: {:#}",
+                                service.vip, service.port, e
+                            );
+                        }
+                        continue;
+                    }
+                    Command::RemoveService {
+                        vip,
+                        port,
+                        protocol,
+                    } => {
+                        if let Err(e) = balancer.remove(vip, port, protocol) {
+                            warn!("{:#}", e);
+                        }
+                        continue;
+                    }
+                    Command::Block {
+                        ip,
+                        vlan,
+                        ttl: block_ttl,
+                    } => {
+                        ttl = block_ttl;
+                        (Target::Source { ip, vlan }, XdpAction::DROP, None)
+                    }
+                    Command::Allow { ip, vlan } => {
+                        (Target::Source { ip, vlan }, XdpAction::PASS, None)
+                    }
+                    Command::Schedule(_) => {
+                        warn!("Nested schedule ignored");
+                        continue;
+                    }
+                    Command::Unschedule { ip, vlan } => {
+                        let target = Target::Source { ip, vlan };
+                        let rules = match layers.get_mut(&target) {
+                            Some(rules) if rules.scheduled.is_some() => rules,
+                            _ => continue,
+                        };
+                        let before = rules.effective();
+                        rules.scheduled = None;
+                        let after = rules.effective();
+                        if after.is_none() {
+                            layers.remove(&target);
+                        }
+                        match entries.update(target, before, after) {
+                            Ok(()) => info!("Scheduled rule for {} removed", target),
+                            Err(e) => {
+                                warn!("Scheduled rule for {} not removed: {:#}", target, e)
+                            }
+                        }
+                        continue;
+                    }
+                    Command::Redirect {
+                        ip,
+                        vlan,
+                        interface,
+                    } => (
+                        Target::Source { ip, vlan },
+                        XdpAction::REDIRECT,
+                        Some(Redirect::Interface(interface)),
+                    ),
+                    Command::Inspect { ip, vlan } => (
+                        Target::Source { ip, vlan },
+                        XdpAction::REDIRECT,
+                        Some(Redirect::Socket),
+                    ),
+                    Command::IcmpBlock { icmp_type, code } => {
+                        (Target::Icmp { icmp_type, code }, XdpAction::DROP, None)
+                    }
+                    Command::IcmpAllow { icmp_type, code } => {
+                        (Target::Icmp { icmp_type, code }, XdpAction::PASS, None)
+                    }
+                };
+                let port = match &redirect {
+                    Some(redirect) => match redirect_ports.port(redirect) {
+                        Ok(port) => port,
+                        Err(e) => {
+                            warn!("Not redirecting {}: {:#}", target, e);
+                            continue;
+                        }
+                    },
+                    None => 0,
+                };
+                let rules = layers.get(&target).cloned().unwrap_or_default();
+                let rule = Rule {
+                    action,
+                    rule_id: next_rule_id,
+                    port,
+                };
+                // Repeated commands for the same entry keep the id it already has. A
+                // block with a time limit is extended, and changes nothing over a
+                // configured block
+                let mut updated = rules.clone();
+                match ttl {
+                    Some(ttl) => {
+                        let until = Instant::now() + ttl;
+                        match rules.block {
+                            Some((block, at)) if same(Some(block), action, port) => {
+                                updated.block = Some((block, at.max(until)));
+                                layers.insert(target, updated);
+                                continue;
+                            }
+                            None if same(rules.base, action, port) => continue,
+                            _ => updated.block = Some((rule, until)),
+                        }
+                    }
+                    None if scheduled => {
+                        if same(rules.scheduled, action, port) {
+                            continue;
+                        }
+                        updated.scheduled = Some(rule);
+                    }
+                    None if same(rules.base, action, port) => continue,
+                    None => updated.base = Some(rule),
+                }
+                if let Err(e) = entries.update(target, rules.effective(), updated.effective()) {
+                    warn!("Rule for {} not installed: {:#}", target, e);
+                    continue;
+                }
+                layers.insert(target, updated);
+                match redirect {
+                    Some(redirect) => info!(
+                        "Rule {}: {} {} to {}",
+                        next_rule_id,
+                        action.to_str(),
+                        target,
+                        redirect
+                    ),
+                    None => match ttl {
+                        Some(ttl) => info!(
+                            "Rule {}: {} {} for {}s",
+                            next_rule_id,
+                            action.to_str(),
+                            target,
+                            ttl.as_secs()
+                        ),
+                        None => info!("Rule {}: {} {}", next_rule_id, action.to_str(), target),
+                    },
+                }
+                next_rule_id += 1;
+            }
+            if batch {
+                if let Err(e) = entries.rule_table.commit() {
+                    warn!("Rule table not replaced: {}", e);
+                }
+            }
         }
     });
     Ok(())
 }
 
-fn parse_and_log_packet(buf: &mut BytesMut) -> Packet {
-    let packet = parse_buf(buf);
+fn parse_and_log_packet(buf: &mut BytesMut, countries: Option<&geoip::Countries>) -> Packet {
+    let mut packet = parse_buf(buf);
+    if let Some(countries) = countries {
+        packet.country = countries.lookup(packet.source);
+    }
     println!("{} - {}", packet.source, packet.destination);
     println!(
-        "LOG: SRC {}, DST {} , packet_type {} - {}, ACTION {}",
+        "LOG: SRC {}, DST {} , packet_type {} - {}, ACTION {}, REASON {}, RULE {}, VLAN {}",
         packet.source,
         packet.destination,
         packet.packet_type.to_str(),
         packet.packet_type as u8,
         packet.action.to_str(),
+        packet.reason.to_str(),
+        packet.rule_id,
+        packet.vlan_id,
     );
+    if packet.packet_type == PacketType::ICMP {
+        match icmp::icmp_type_name(packet.icmp_type) {
+            Some(name) => println!("ICMP {} code {}", name, packet.icmp_code),
+            None => println!("ICMP type {} code {}", packet.icmp_type, packet.icmp_code),
+        }
+    }
+    if packet.source_port != 0 || packet.destination_port != 0 {
+        println!("PORTS {} → {}", packet.source_port, packet.destination_port);
+    }
+    match packet.reason {
+        Reason::UNLOCKED => println!("KNOCK gate {} opened", packet.rule_id),
+        Reason::MISKNOCK => println!("KNOCK gate {} failed, sequence reset", packet.rule_id),
+        _ => {}
+    }
+    if let Some(country) = packet.country {
+        println!("COUNTRY {}", String::from_utf8_lossy(&country));
+    }
+    if packet.sample_rate > 1 {
+        println!("SAMPLED 1 in {}", packet.sample_rate);
+    }
+    if packet.tunnel != TunnelType::NONE {
+        println!(
+            "TUNNEL {}: OUTER SRC {}, DST {}",
+            packet.tunnel.to_str(),
+            packet.outer_source,
+            packet.outer_destination,
+        );
+    }
     packet
 }
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     let opt = Opt::from_args();
+    if let Some(path) = &opt.import {
+        return import::import(path, &opt.iface);
+    }
+    let config = match &opt.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if let Some(format) = opt.export {
+        print!(
+            "{}",
+            export::export(&config, format, &opt.iface, opt.bogons, opt.wan)?
+        );
+        return Ok(());
+    }
 
     TermLogger::init(
         LevelFilter::Debug,
@@ -110,18 +672,116 @@ async fn main() -> Result<(), anyhow::Error> {
     // runtime. This approach is recommended for most real-world use cases. If you would
     // like to specify the eBPF program at runtime rather than at compile-time, you can
     // reach for `Bpf::load_file` instead.
-    let mut bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/release/ebpfapp"
-    ))?;
+    // The rule tables map is created here and the loader picks it up from its pin
+    // Held until exit, so a second instance can't replace the rule tables under this one
+    let pins = rules::lock_pins()?;
+    rules::create_rule_tables(&pins)
+        .context("failed to create the rule tables, is /sys/fs/bpf mounted?")?;
+    let mut bpf = BpfLoader::new()
+        .map_pin_path(rules::PIN_PATH)
+        .load(include_bytes_aligned!(
+            "../../target/bpfel-unknown-none/release/ebpfapp"
+        ))?;
     let program: &mut Xdp = bpf.program_mut("ebpfapp").unwrap().try_into()?;
     program.load()?;
     program.attach(&opt.iface, XdpFlags::default())
         .context("failed to attach the XDP program with default flags - try changing XdpFlags::default() to XdpFlags::SKB_MODE")?;
 
+    checks::load_checks(&bpf, &opt.checks)?;
+    // Summaries replace the per packet events unless a rate asks for them
+    let default_rate = if opt.aggregate.is_some() { 0 } else { 1 };
+    sampling::load_samples(&bpf, &opt.sample, default_rate)?;
+    if !config.nat.is_empty() {
+        nat::attach_egress(&mut bpf, &opt.iface)?;
+    }
+
+    let metrics = Arc::new(Metrics::default());
+
+    fragments::load_policies(&bpf, &opt.fragments)?;
+    tunnels::load_tunnels(&bpf, &opt.tunnels)?;
+    metrics.add_counters(fragments::fragment_counters(&bpf)?);
+    metrics.add_counters(icmp::icmp_counters(&bpf)?);
+
+    if opt.echo {
+        echo::load_echo(&bpf, &opt.iface, opt.echo_rate)?;
+        metrics.add_counters(echo::echo_counters(&bpf)?);
+    }
+
+    if opt.xsk {
+        xsk::spawn_consumers(
+            &bpf,
+            &opt.iface,
+            opt.xsk_queues,
+            opt.xsk_reinject.as_deref(),
+        )?;
+    }
+
+    if opt.bogons {
+        bogons::load_bogons(&bpf, &opt.iface, opt.wan)?;
+        metrics.add_counters(bogons::bogon_counters(&bpf)?);
+    }
+
+    let geoip_blocks = matches!(&config.geoip, Some(geoip) if !geoip.block.is_empty());
+    if !config.blocklists.is_empty() || geoip_blocks {
+        blocklist::load_blocklists(
+            blocklist::Blocklists::new(&bpf, config.blocklists.clone(), config.geoip.clone())?,
+            Duration::from_secs(opt.blocklist_refresh),
+        )
+        .await?;
+    }
+
+    if !config.knocks.is_empty() {
+        knock::load_knocks(&bpf, &config.knocks)?;
+    }
+
     let (tx, rx) = mpsc::channel::<Command>(32);
-    process_actions(&bpf, rx)?;
+    process_actions(&bpf, &opt.iface, rx)?;
+    tx.send(Command::Batch(config.commands())).await?;
+    schedule::spawn_scheduler(config.scheduled_rules(), tx.clone());
+    if let Some(path) = &opt.config {
+        lb::spawn_service_reload(path.clone(), config.services.clone(), tx.clone())?;
+    }
+
+    metrics::log_counters(metrics.clone(), Duration::from_secs(10));
+    if let Some(secs) = opt.aggregate {
+        aggregate::log_aggregates(aggregate::Aggregates::new(&bpf)?, Duration::from_secs(secs));
+    }
+    if let Some(collector) = opt.flow_collector {
+        // The interface index tells apart exporters running on several interfaces
+        let domain = nix::net::if_::if_nametoindex(opt.iface.as_str())?;
+        flows::export_flows(
+            aggregate::Aggregates::new(&bpf)?,
+            flows::Exporter::new(opt.flow_format, domain),
+            collector,
+            Duration::from_secs(opt.flow_interval),
+        );
+    }
+    if let Some(collector) = opt.sflow_collector {
+        if opt.sflow_rate == 0 {
+            anyhow::bail!("--sflow-rate must be at least 1");
+        }
+        sflow::start_sflow(
+            &bpf,
+            &opt.iface,
+            collector,
+            opt.sflow_rate,
+            Duration::from_secs(opt.sflow_counters),
+        )
+        .await?;
+    }
+    if let Some(addr) = opt.metrics_addr {
+        metrics::serve(metrics.clone(), addr).await?;
+    }
 
-    process_bpf_events(&bpf, &tx)?;
+    watch::spawn_watchers(&config.watches, &tx);
+    if let Some(detection) = config.detection.clone() {
+        detect::spawn_detectors(&bpf, detection, tx.clone())?;
+    }
+    let countries = match &config.geoip {
+        Some(geoip) => Some(Arc::new(geoip::Countries::open(&geoip.database)?)),
+        None => None,
+    };
+    process_bpf_events(&bpf, &tx, &metrics, countries)?;
 
     info!("Listening on {}", &opt.iface);
     info!("Waiting for Ctrl-C...");
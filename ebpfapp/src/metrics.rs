@@ -0,0 +1,192 @@
+use aya::maps::{MapRef, PerCpuArray};
+use aya::Bpf;
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::parser::{Packet, ParserToString};
+
+// Packet series kept before new rule ids are counted as rule_id="other", as
+// every block from a detector or watch gets a rule id of its own
+const MAX_PACKET_SERIES: usize = 1024;
+
+// Labels a packet counter is broken down by. A rule id of None stands for the
+// rules past MAX_PACKET_SERIES
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct PacketLabels {
+    action: &'static str,
+    reason: &'static str,
+    rule_id: Option<u32>,
+}
+
+// A per cpu counter array kept by the XDP program, exported as one counter
+// with a label per array index
+pub struct KernelCounters {
+    metric: &'static str,
+    label: &'static str,
+    names: Vec<(u32, &'static str)>,
+    map: PerCpuArray<MapRef, u64>,
+}
+
+impl KernelCounters {
+    pub fn new(
+        bpf: &Bpf,
+        map: &str,
+        metric: &'static str,
+        label: &'static str,
+        names: Vec<(u32, &'static str)>,
+    ) -> Result<Self, anyhow::Error> {
+        Ok(KernelCounters {
+            metric,
+            label,
+            names,
+            map: PerCpuArray::try_from(bpf.map(map)?)?,
+        })
+    }
+
+    // Sum the per cpu values to get the total for each index
+    fn totals(&self) -> Vec<(&'static str, u64)> {
+        self.names
+            .iter()
+            .filter_map(|&(index, name)| {
+                let values = self.map.get(&index, 0).ok()?;
+                Some((name, values.iter().sum()))
+            })
+            .collect()
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    packets: Mutex<BTreeMap<PacketLabels, u64>>,
+    counters: Mutex<Vec<KernelCounters>>,
+}
+
+impl Metrics {
+    pub fn record(&self, packet: &Packet) {
+        let mut labels = PacketLabels {
+            action: packet.action.to_str(),
+            reason: packet.reason.to_str(),
+            rule_id: Some(packet.rule_id),
+        };
+        let mut packets = self.packets.lock().unwrap();
+        if packets.len() >= MAX_PACKET_SERIES && !packets.contains_key(&labels) {
+            labels.rule_id = None;
+        }
+        // A sampled event stands for sample_rate packets
+        *packets.entry(labels).or_insert(0) += packet.sample_rate as u64;
+    }
+
+    pub fn add_counters(&self, counters: KernelCounters) {
+        self.counters.lock().unwrap().push(counters);
+    }
+
+    // Render every counter in the prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP ebpfapp_packets_total Packets reported by the XDP program.\n");
+        out.push_str("# TYPE ebpfapp_packets_total counter\n");
+        for (labels, count) in self.packets.lock().unwrap().iter() {
+            let rule_id = match labels.rule_id {
+                Some(rule_id) => rule_id.to_string(),
+                None => "other".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "ebpfapp_packets_total{{action=\"{}\",reason=\"{}\",rule_id=\"{}\"}} {}",
+                labels.action, labels.reason, rule_id, count
+            );
+        }
+        for counters in self.counters.lock().unwrap().iter() {
+            let _ = writeln!(out, "# TYPE {} counter", counters.metric);
+            for (name, count) in counters.totals() {
+                let _ = writeln!(
+                    out,
+                    "{}{{{}=\"{}\"}} {}",
+                    counters.metric, counters.label, name, count
+                );
+            }
+        }
+        out
+    }
+}
+
+// Periodically log every kernel counter that has been hit
+pub fn log_counters(metrics: Arc<Metrics>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            for counters in metrics.counters.lock().unwrap().iter() {
+                for (name, count) in counters.totals() {
+                    if count > 0 {
+                        info!("{} {} {}", counters.metric, name, count);
+                    }
+                }
+            }
+        }
+    });
+}
+
+// Reads the request up to the blank line after its headers, so the reply isn't
+// written while the client is still sending and the close doesn't reset it
+async fn read_request(socket: &mut TcpStream) -> Result<(), anyhow::Error> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|end| end == b"\r\n\r\n") {
+        if request.len() > 8192 {
+            anyhow::bail!("request headers too long");
+        }
+        let read = socket.read(&mut buf).await?;
+        if read == 0 {
+            anyhow::bail!("connection closed before the end of the request");
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    Ok(())
+}
+
+async fn respond(metrics: &Metrics, mut socket: TcpStream) -> Result<(), anyhow::Error> {
+    tokio::time::timeout(Duration::from_secs(10), read_request(&mut socket)).await??;
+    let body = metrics.render();
+    let response = format!(
+        "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
+
+// Minimal HTTP endpoint so the counters can be scraped, every request gets the
+// metrics. Each connection is served by a task of its own so a slow client
+// doesn't hold up the others
+pub async fn serve(metrics: Arc<Metrics>, addr: SocketAddr) -> Result<(), anyhow::Error> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Serving metrics on {}", addr);
+    tokio::spawn(async move {
+        loop {
+            let (socket, peer) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("Failed to accept metrics connection: {}", e);
+                    continue;
+                }
+            };
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                if let Err(e) = respond(&metrics, socket).await {
+                    warn!("Metrics request from {} failed: {:#}", peer, e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
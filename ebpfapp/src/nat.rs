@@ -0,0 +1,124 @@
+use anyhow::bail;
+use aya::maps::{HashMap, MapRefMut};
+use aya::programs::{tc, SchedClassifier, TcAttachType};
+use aya::Bpf;
+use ebpfapp_common::{NatKey, NatTarget};
+use log::info;
+use serde::Deserialize;
+use std::convert::{TryFrom, TryInto};
+use std::net::Ipv4Addr;
+
+use crate::lb::Protocol;
+
+// A 1:1 NAT between two addresses, or a port forward when protocol and port are set, e.g.
+//
+// [[nat]]
+// public = "203.0.113.5"
+// private = "10.0.0.5"
+// protocol = "tcp"
+// port = 8080
+// to_port = 80
+#[derive(Debug, Clone, Deserialize)]
+pub struct NatConfig {
+    pub public: Ipv4Addr,
+    pub private: Ipv4Addr,
+    pub protocol: Option<Protocol>,
+    pub port: Option<u16>,
+    // Port on the private address, the public port when missing
+    pub to_port: Option<u16>,
+}
+
+impl NatConfig {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.protocol.is_some() != self.port.is_some() {
+            bail!("a port forward needs both a protocol and a port");
+        }
+        if self.to_port.is_some() && self.port.is_none() {
+            bail!("to_port is only valid for a port forward");
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for NatConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.protocol, self.port) {
+            (Some(protocol), Some(port)) => write!(
+                f,
+                "{}:{} {:?} <-> {}:{}",
+                self.public,
+                port,
+                protocol,
+                self.private,
+                self.to_port.unwrap_or(port)
+            ),
+            _ => write!(f, "{} <-> {}", self.public, self.private),
+        }
+    }
+}
+
+// Installs the entries of the DNAT map, translating inbound packets in XDP, and the
+// SNAT map, translating the replies in the TC egress classifier
+pub struct Nat {
+    dnat: HashMap<MapRefMut, NatKey, NatTarget>,
+    snat: HashMap<MapRefMut, NatKey, NatTarget>,
+}
+
+impl Nat {
+    pub fn new(bpf: &Bpf) -> Result<Self, anyhow::Error> {
+        Ok(Nat {
+            dnat: HashMap::try_from(bpf.map_mut("DNAT")?)?,
+            snat: HashMap::try_from(bpf.map_mut("SNAT")?)?,
+        })
+    }
+
+    pub fn apply(&mut self, config: &NatConfig) -> Result<(), anyhow::Error> {
+        config.validate()?;
+        let protocol = config.protocol.map_or(0, |protocol| protocol.number());
+        let port = config.port.unwrap_or(0);
+        let to_port = config.to_port.unwrap_or(port);
+
+        let inbound = NatKey {
+            address: u32::from(config.public),
+            port,
+            protocol,
+            _pad: 0,
+        };
+        let outbound = NatKey {
+            address: u32::from(config.private),
+            port: to_port,
+            protocol,
+            _pad: 0,
+        };
+        self.dnat.insert(
+            inbound,
+            NatTarget {
+                address: u32::from(config.private),
+                port: to_port,
+                _pad: 0,
+            },
+            0,
+        )?;
+        self.snat.insert(
+            outbound,
+            NatTarget {
+                address: u32::from(config.public),
+                port,
+                _pad: 0,
+            },
+            0,
+        )?;
+        info!("NAT {}", config);
+        Ok(())
+    }
+}
+
+// The XDP program only sees inbound packets, replies are translated on egress
+pub fn attach_egress(bpf: &mut Bpf, iface: &str) -> Result<(), anyhow::Error> {
+    // Fails when the interface already has a clsact qdisc, which is fine
+    let _ = tc::qdisc_add_clsact(iface);
+    let program: &mut SchedClassifier = bpf.program_mut("ebpfapp_egress").unwrap().try_into()?;
+    program.load()?;
+    program.attach(iface, TcAttachType::Egress)?;
+    Ok(())
+}
@@ -1,12 +1,30 @@
 use bytes::BytesMut;
-use ebpfapp_common::{PacketType, XdpAction};
+use ebpfapp_common::{
+    BogonType, Check, EchoCount, ForwardMode, FragmentCount, FragmentPolicy, PacketType, Reason,
+    TunnelType, XdpAction,
+};
 use std::net::Ipv4Addr;
 
+#[derive(Clone, Copy)]
 pub struct Packet {
     pub source: Ipv4Addr,
     pub destination: Ipv4Addr,
     pub action: XdpAction,
     pub packet_type: PacketType,
+    pub reason: Reason,
+    pub rule_id: u32,
+    pub vlan_id: u16,
+    pub tunnel: TunnelType,
+    pub outer_source: Ipv4Addr,
+    pub outer_destination: Ipv4Addr,
+    pub icmp_type: u8,
+    pub icmp_code: u8,
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub sample_rate: u32,
+    // ISO code of the source's country, looked up in userspace when a GeoIP
+    // database is configured
+    pub country: Option<[u8; 2]>,
 }
 
 //New type for to_str
@@ -37,6 +55,103 @@ impl ParserToString for XdpAction {
     }
 }
 
+impl ParserToString for Reason {
+    fn to_str(&self) -> &'static str {
+        match self {
+            Reason::NONE => "NONE",
+            Reason::LISTED => "LISTED",
+            Reason::BOGON => "BOGON",
+            Reason::MALFORMED => "MALFORMED",
+            Reason::FRAGMENT => "FRAGMENT",
+            Reason::ICMP => "ICMP",
+            Reason::ECHO => "ECHO",
+            Reason::SERVICE => "SERVICE",
+            Reason::BLOCKLIST => "BLOCKLIST",
+            Reason::KNOCK => "KNOCK",
+            Reason::UNLOCKED => "UNLOCKED",
+            Reason::MISKNOCK => "MISKNOCK",
+        }
+    }
+}
+
+impl ParserToString for ForwardMode {
+    fn to_str(&self) -> &'static str {
+        match self {
+            ForwardMode::MAC => "MAC",
+            ForwardMode::IPIP => "IPIP",
+        }
+    }
+}
+
+impl ParserToString for EchoCount {
+    fn to_str(&self) -> &'static str {
+        match self {
+            EchoCount::REPLIED => "REPLIED",
+            EchoCount::LIMITED => "LIMITED",
+        }
+    }
+}
+
+impl ParserToString for FragmentPolicy {
+    fn to_str(&self) -> &'static str {
+        match self {
+            FragmentPolicy::DROP => "DROP",
+            FragmentPolicy::TINY => "TINY",
+            FragmentPolicy::OVERLAP => "OVERLAP",
+        }
+    }
+}
+
+impl ParserToString for FragmentCount {
+    fn to_str(&self) -> &'static str {
+        match self {
+            FragmentCount::FIRST => "FIRST",
+            FragmentCount::LATER => "LATER",
+            FragmentCount::DROPPED => "DROPPED",
+            FragmentCount::TINY => "TINY",
+            FragmentCount::OVERLAP => "OVERLAP",
+        }
+    }
+}
+
+impl ParserToString for Check {
+    fn to_str(&self) -> &'static str {
+        match self {
+            Check::TRUNCATED => "TRUNCATED",
+            Check::VERSION => "VERSION",
+            Check::IHL => "IHL",
+            Check::LENGTH => "LENGTH",
+            Check::FRAGMENT => "FRAGMENT",
+            Check::TCPFLAGS => "TCPFLAGS",
+            Check::OPTIONS => "OPTIONS",
+        }
+    }
+}
+
+impl ParserToString for BogonType {
+    fn to_str(&self) -> &'static str {
+        match self {
+            BogonType::PRIVATE => "PRIVATE",
+            BogonType::LOOPBACK => "LOOPBACK",
+            BogonType::MULTICAST => "MULTICAST",
+            BogonType::THISNET => "THISNET",
+            BogonType::RESERVED => "RESERVED",
+            BogonType::LOCAL => "LOCAL",
+        }
+    }
+}
+
+impl ParserToString for TunnelType {
+    fn to_str(&self) -> &'static str {
+        match self {
+            TunnelType::NONE => "NONE",
+            TunnelType::IPIP => "IPIP",
+            TunnelType::GRE => "GRE",
+            TunnelType::VXLAN => "VXLAN",
+        }
+    }
+}
+
 pub fn parse_buf(buf: &mut BytesMut) -> Packet {
     let ptr = buf.as_ptr().cast::<ebpfapp_common::PacketLog>();
     let data = unsafe { ptr.read_unaligned() };
@@ -47,5 +162,17 @@ pub fn parse_buf(buf: &mut BytesMut) -> Packet {
         destination: dst_addr,
         action: data.action,
         packet_type: data.packet_type,
+        reason: data.reason,
+        rule_id: data.rule_id,
+        vlan_id: data.vlan_id,
+        tunnel: data.tunnel,
+        outer_source: Ipv4Addr::from(data.outer_source),
+        outer_destination: Ipv4Addr::from(data.outer_destination),
+        icmp_type: data.icmp_type,
+        icmp_code: data.icmp_code,
+        source_port: data.source_port,
+        destination_port: data.destination_port,
+        sample_rate: data.sample_rate,
+        country: None,
     }
 }
@@ -0,0 +1,63 @@
+use anyhow::{bail, Context};
+use aya::maps::MapRefMut;
+use aya::Bpf;
+use ebpfapp_common::{REDIRECT_PORTS, XSK_PORT};
+use log::info;
+use nix::net::if_::if_nametoindex;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::sys;
+
+// Where a REDIRECT rule sends packets
+#[derive(Debug, Clone, PartialEq)]
+pub enum Redirect {
+    Interface(String),
+    // The AF_XDP socket of the receive queue, for inspection in userspace
+    Socket,
+}
+
+impl fmt::Display for Redirect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Redirect::Interface(iface) => write!(f, "{}", iface),
+            Redirect::Socket => write!(f, "AF_XDP"),
+        }
+    }
+}
+
+// Interfaces REDIRECT rules send packets to, each one gets a port in the
+// REDIRECT_PORTS devmap the first time a rule uses it
+pub struct RedirectPorts {
+    map: MapRefMut,
+    ports: BTreeMap<String, u32>,
+}
+
+impl RedirectPorts {
+    pub fn new(bpf: &Bpf) -> Result<Self, anyhow::Error> {
+        Ok(RedirectPorts {
+            map: bpf.map_mut("REDIRECT_PORTS")?,
+            ports: BTreeMap::new(),
+        })
+    }
+
+    pub fn port(&mut self, redirect: &Redirect) -> Result<u32, anyhow::Error> {
+        let iface = match redirect {
+            Redirect::Interface(iface) => iface,
+            Redirect::Socket => return Ok(XSK_PORT),
+        };
+        if let Some(port) = self.ports.get(iface) {
+            return Ok(*port);
+        }
+        let port = self.ports.len() as u32;
+        if port >= REDIRECT_PORTS {
+            bail!("no redirect port left for {}", iface);
+        }
+        let ifindex = if_nametoindex(iface.as_str())
+            .with_context(|| format!("unknown redirect interface {}", iface))?;
+        sys::map_update(&self.map, &port, &ifindex, 0)?;
+        self.ports.insert(iface.to_string(), port);
+        info!("Redirect port {}: {}", port, iface);
+        Ok(port)
+    }
+}
@@ -0,0 +1,136 @@
+use anyhow::{bail, Context};
+use aya::maps::MapRefMut;
+use aya::Bpf;
+use ebpfapp_common::{Rule, RULE_ENTRIES};
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use crate::sys::{self, MapFd, BPF_MAP_TYPE_ARRAY_OF_MAPS, BPF_MAP_TYPE_HASH};
+
+// Where RULE_TABLES is pinned for the loader to find, on the bpf filesystem
+pub const PIN_PATH: &str = "/sys/fs/bpf/ebpfapp";
+
+fn rule_table() -> Result<MapFd, io::Error> {
+    MapFd::create(
+        BPF_MAP_TYPE_HASH,
+        mem::size_of::<u32>(),
+        mem::size_of::<Rule>(),
+        RULE_ENTRIES,
+        None,
+    )
+}
+
+// Lock on the pin directory, held by the running instance until it exits
+pub struct PinLock {
+    _dir: fs::File,
+}
+
+// Takes the pin directory for this instance. Fails while another one holds it, as
+// replacing RULE_TABLES would take the rule tables from its program
+pub fn lock_pins() -> Result<PinLock, anyhow::Error> {
+    fs::create_dir_all(PIN_PATH)?;
+    let dir = fs::File::open(PIN_PATH).with_context(|| format!("failed to open {}", PIN_PATH))?;
+    match flock(dir.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(()) => Ok(PinLock { _dir: dir }),
+        Err(Errno::EWOULDBLOCK) => bail!("{} is in use by another ebpfapp", PIN_PATH),
+        Err(e) => Err(e).with_context(|| format!("failed to lock {}", PIN_PATH)),
+    }
+}
+
+// Creates RULE_TABLES and pins it, which has to happen before the program is
+// loaded as aya can't create a map of maps. Needs the PinLock, so the pin being
+// replaced can only be one a previous run left behind
+pub fn create_rule_tables(_lock: &PinLock) -> Result<(), anyhow::Error> {
+    fs::create_dir_all(PIN_PATH)?;
+    let path = Path::new(PIN_PATH).join("RULE_TABLES");
+    // A map left by an earlier run may have been made for another Rule layout
+    if path.exists() {
+        fs::remove_file(&path)?;
+    }
+    // Only the shape of the template matters, the kernel doesn't keep it
+    let template = rule_table()?;
+    let tables = MapFd::create(BPF_MAP_TYPE_ARRAY_OF_MAPS, 4, 4, 1, Some(&template))?;
+    tables.pin(&path)?;
+    Ok(())
+}
+
+// The rule table of the sources without a VLAN. Single rules are written to the
+// table in use, a batch goes to a copy of it that replaces the table in one update
+pub struct RuleTable {
+    tables: MapRefMut,
+    current: MapFd,
+    rules: BTreeMap<u32, Rule>,
+    staged: Option<(MapFd, BTreeMap<u32, Rule>)>,
+}
+
+impl RuleTable {
+    pub fn new(bpf: &Bpf) -> Result<Self, anyhow::Error> {
+        let tables = bpf.map_mut("RULE_TABLES")?;
+        let current = rule_table()?;
+        sys::map_update(&tables, &0u32, &(current.as_raw_fd() as u32), 0)?;
+        Ok(RuleTable {
+            tables,
+            current,
+            rules: BTreeMap::new(),
+            staged: None,
+        })
+    }
+
+    pub fn insert(&mut self, source: Ipv4Addr, rule: Rule) -> Result<(), io::Error> {
+        let key = u32::from(source);
+        match &mut self.staged {
+            Some((table, rules)) => {
+                table.update(&key, &rule, 0)?;
+                rules.insert(key, rule);
+            }
+            None => {
+                self.current.update(&key, &rule, 0)?;
+                self.rules.insert(key, rule);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, source: Ipv4Addr) -> Result<(), io::Error> {
+        let key = u32::from(source);
+        match &mut self.staged {
+            Some((table, rules)) => {
+                table.delete(&key)?;
+                rules.remove(&key);
+            }
+            None => {
+                self.current.delete(&key)?;
+                self.rules.remove(&key);
+            }
+        }
+        Ok(())
+    }
+
+    // Sends the following inserts and removes to a copy of the table until commit
+    pub fn stage(&mut self) -> Result<(), io::Error> {
+        let table = rule_table()?;
+        for (key, rule) in &self.rules {
+            table.update(key, rule, 0)?;
+        }
+        self.staged = Some((table, self.rules.clone()));
+        Ok(())
+    }
+
+    // Points RULE_TABLES at the staged copy, the old table goes once the last
+    // program using it is done
+    pub fn commit(&mut self) -> Result<(), io::Error> {
+        if let Some((table, rules)) = self.staged.take() {
+            sys::map_update(&self.tables, &0u32, &(table.as_raw_fd() as u32), 0)?;
+            self.current = table;
+            self.rules = rules;
+        }
+        Ok(())
+    }
+}
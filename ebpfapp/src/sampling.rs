@@ -0,0 +1,63 @@
+use aya::maps::Array;
+use aya::Bpf;
+use ebpfapp_common::{XdpAction, CONFIG_SAMPLE_RATES};
+use log::info;
+use std::convert::TryFrom;
+
+use crate::parser::ParserToString;
+
+const ACTION_LIST: [XdpAction; 5] = [
+    XdpAction::ABORTED,
+    XdpAction::DROP,
+    XdpAction::PASS,
+    XdpAction::TX,
+    XdpAction::REDIRECT,
+];
+
+// 1 in N packets of an action get an event, 0 disables the action's events
+#[derive(Debug, Clone, Copy)]
+pub struct SampleRate {
+    pub action: XdpAction,
+    pub rate: u32,
+}
+
+// Parses action=rate, e.g. pass=100
+pub fn parse_sample(s: &str) -> Result<SampleRate, String> {
+    let (action, rate) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected action=rate, got {}", s))?;
+    let action = match action {
+        "drop" => XdpAction::DROP,
+        "pass" => XdpAction::PASS,
+        "tx" => XdpAction::TX,
+        "redirect" => XdpAction::REDIRECT,
+        _ => return Err(format!("unknown action {}", action)),
+    };
+    let rate = rate
+        .parse()
+        .map_err(|_| format!("invalid sample rate {}", rate))?;
+    Ok(SampleRate { action, rate })
+}
+
+// Actions without a rate of their own use default_rate
+pub fn load_samples(
+    bpf: &Bpf,
+    samples: &[SampleRate],
+    default_rate: u32,
+) -> Result<(), anyhow::Error> {
+    let mut config: Array<_, u32> = Array::try_from(bpf.map_mut("CONFIG")?)?;
+    for action in ACTION_LIST {
+        let rate = samples
+            .iter()
+            .rev()
+            .find(|sample| sample.action == action)
+            .map_or(default_rate, |sample| sample.rate);
+        config.set(CONFIG_SAMPLE_RATES + action as u32, rate, 0)?;
+        match rate {
+            0 => info!("{} events: off", action.to_str()),
+            1 => {}
+            _ => info!("{} events: 1 in {}", action.to_str(), rate),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,243 @@
+use anyhow::bail;
+use chrono::{DateTime, Datelike, Local, Timelike, Weekday};
+use log::{info, warn};
+use serde::{Deserialize, Deserializer};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::config::RuleConfig;
+use crate::Command;
+
+// Minutes after midnight a window starts and ends, it ends the next day when the
+// end comes first
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hours {
+    start: u32,
+    end: u32,
+}
+
+fn parse_time(s: &str) -> Option<u32> {
+    let (hour, minute) = s.trim().split_once(':')?;
+    let (hour, minute): (u32, u32) = (hour.parse().ok()?, minute.parse().ok()?);
+    // 24:00 ends a window at midnight
+    if hour > 24 || minute > 59 || (hour == 24 && minute != 0) {
+        return None;
+    }
+    Some(hour * 60 + minute)
+}
+
+pub fn parse_hours(s: &str) -> Result<Hours, String> {
+    let (start, end) = s
+        .split_once('-')
+        .ok_or_else(|| format!("hours {} are not a span like 22:00-02:00", s))?;
+    match (parse_time(start), parse_time(end)) {
+        (Some(start), Some(end)) if start % 1440 != end % 1440 => Ok(Hours {
+            start: start % 1440,
+            end: end % 1440,
+        }),
+        (Some(_), Some(_)) => Err(format!("hours {} start and end at the same time", s)),
+        _ => Err(format!("hours {} are not a span like 22:00-02:00", s)),
+    }
+}
+
+fn hours<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Hours>, D::Error> {
+    let hours = String::deserialize(deserializer)?;
+    parse_hours(&hours)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn days<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Weekday>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|day| {
+            Weekday::from_str(day)
+                .map_err(|_| serde::de::Error::custom(format!("unknown day {}", day)))
+        })
+        .collect()
+}
+
+// cron takes a seconds field first, the usual five fields get one. Its days of
+// the week count from Sunday as 1, so names are clearer than numbers
+fn cron<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<cron::Schedule>, D::Error> {
+    let expression = String::deserialize(deserializer)?;
+    let expression = match expression.split_whitespace().count() {
+        5 => format!("0 {}", expression),
+        _ => expression,
+    };
+    cron::Schedule::from_str(&expression)
+        .map(Some)
+        .map_err(|e| serde::de::Error::custom(format!("invalid cron {}: {}", expression, e)))
+}
+
+// When a rule is in effect, in local time. Either days and hours, e.g.
+//
+// [rule.schedule]
+// days = ["sat", "sun"]
+// hours = "02:00-06:00"
+//
+// or windows that start on a cron expression and last duration seconds
+//
+// [rule.schedule]
+// cron = "0 2 * * sat"
+// duration = 14400
+// invert = true
+#[derive(Debug, Clone, Deserialize)]
+pub struct Schedule {
+    // Every day when empty
+    #[serde(default, deserialize_with = "days")]
+    pub days: Vec<Weekday>,
+    // The whole day when missing
+    #[serde(default, deserialize_with = "hours")]
+    pub hours: Option<Hours>,
+    #[serde(default, deserialize_with = "cron")]
+    pub cron: Option<cron::Schedule>,
+    pub duration: Option<u64>,
+    // The rule is in effect outside of the windows instead
+    #[serde(default)]
+    pub invert: bool,
+}
+
+impl Schedule {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        let windows = !self.days.is_empty() || self.hours.is_some();
+        match (&self.cron, self.duration) {
+            (Some(_), _) if windows => bail!("a schedule has either cron or days and hours"),
+            (Some(_), None) => bail!("a cron schedule needs a duration"),
+            (Some(_), Some(0)) => bail!("duration must be at least 1"),
+            (None, Some(_)) => bail!("duration is only valid with cron"),
+            (None, None) if !windows => bail!("a schedule needs cron, days or hours"),
+            _ => Ok(()),
+        }
+    }
+
+    fn on_day(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    fn in_window(&self, now: DateTime<Local>) -> bool {
+        if let (Some(cron), Some(duration)) = (&self.cron, self.duration) {
+            // The window started within the last duration if the next start after
+            // that point has already come
+            let since = now - chrono::Duration::seconds(duration as i64);
+            return matches!(cron.after(&since).next(), Some(start) if start <= now);
+        }
+        let day = now.weekday();
+        let minute = now.hour() * 60 + now.minute();
+        match self.hours {
+            None => self.on_day(day),
+            Some(Hours { start, end }) if start < end => {
+                self.on_day(day) && (start..end).contains(&minute)
+            }
+            // Past midnight the window belongs to the day it started on
+            Some(Hours { start, end }) => {
+                (self.on_day(day) && minute >= start) || (self.on_day(day.pred()) && minute < end)
+            }
+        }
+    }
+
+    pub fn active(&self, now: DateTime<Local>) -> bool {
+        self.in_window(now) != self.invert
+    }
+}
+
+// Installs each scheduled rule while its schedule is active and takes it out when
+// the schedule ends, which leaves other rules for the source in place
+pub fn spawn_scheduler(rules: Vec<RuleConfig>, tx: mpsc::Sender<Command>) {
+    if rules.is_empty() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut active = vec![false; rules.len()];
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            ticker.tick().await;
+            let now = Local::now();
+            for (rule, active) in rules.iter().zip(active.iter_mut()) {
+                let schedule = match &rule.schedule {
+                    Some(schedule) => schedule,
+                    None => continue,
+                };
+                if schedule.active(now) == *active {
+                    continue;
+                }
+                *active = !*active;
+                let command = if *active {
+                    info!("Scheduled rule for {} starts", rule.source);
+                    Command::Schedule(Box::new(rule.command()))
+                } else {
+                    info!("Scheduled rule for {} ends", rule.source);
+                    Command::Unschedule {
+                        ip: rule.source,
+                        vlan: rule.vlan,
+                    }
+                };
+                if tx.send(command).await.is_err() {
+                    warn!("Scheduler stopped, the rules are no longer applied");
+                    return;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn schedule(toml: &str) -> Schedule {
+        let schedule: Schedule = toml::from_str(toml).unwrap();
+        schedule.validate().unwrap();
+        schedule
+    }
+
+    // June 2024 has no DST change, the 1st is a Saturday
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(2024, 6, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn hours_past_midnight_belong_to_the_day_they_start() {
+        let schedule = schedule("days = [\"sat\"]\nhours = \"22:00-02:00\"");
+        assert!(!schedule.in_window(at(1, 21, 59)));
+        assert!(schedule.in_window(at(1, 22, 0)));
+        assert!(schedule.in_window(at(1, 23, 30)));
+        assert!(schedule.in_window(at(2, 1, 59)));
+        assert!(!schedule.in_window(at(2, 2, 0)));
+        // Sunday evening starts no window, Saturday morning is the end of Friday's
+        assert!(!schedule.in_window(at(2, 23, 0)));
+        assert!(!schedule.in_window(at(1, 1, 0)));
+    }
+
+    #[test]
+    fn hours_within_a_day() {
+        let schedule = schedule("hours = \"02:00-06:00\"");
+        assert!(!schedule.in_window(at(3, 1, 59)));
+        assert!(schedule.in_window(at(3, 2, 0)));
+        assert!(schedule.in_window(at(4, 5, 59)));
+        assert!(!schedule.in_window(at(4, 6, 0)));
+    }
+
+    #[test]
+    fn invert_is_active_outside_the_windows() {
+        let schedule = schedule("days = [\"sat\"]\nhours = \"22:00-02:00\"\ninvert = true");
+        assert!(!schedule.active(at(1, 23, 0)));
+        assert!(!schedule.active(at(2, 1, 0)));
+        assert!(schedule.active(at(2, 3, 0)));
+        assert!(schedule.active(at(1, 12, 0)));
+    }
+
+    #[test]
+    fn cron_window_lasts_the_duration() {
+        let schedule = schedule("cron = \"0 3 * * mon\"\nduration = 7200");
+        assert!(!schedule.in_window(at(3, 2, 59)));
+        assert!(schedule.in_window(at(3, 3, 0)));
+        assert!(schedule.in_window(at(3, 4, 59)));
+        assert!(!schedule.in_window(at(3, 5, 0)));
+        assert!(!schedule.in_window(at(4, 3, 30)));
+    }
+}
@@ -0,0 +1,328 @@
+use aya::maps::perf::AsyncPerfEventArray;
+use aya::maps::{Array, MapRef, PerCpuArray};
+use aya::util::online_cpus;
+use aya::Bpf;
+use bytes::BytesMut;
+use ebpfapp_common::{SflowSample, XdpAction, CONFIG_SFLOW_RATE};
+use log::{info, warn};
+use nix::net::if_::if_nametoindex;
+use std::convert::TryFrom;
+use std::fs;
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::task;
+
+use crate::bogons::interface_addresses;
+
+const SFLOW_VERSION: u32 = 5;
+const ADDRESS_IPV4: u32 = 1;
+// Sample and record formats of the standard enterprise 0
+const FLOW_SAMPLE: u32 = 1;
+const COUNTER_SAMPLE: u32 = 2;
+const RAW_PACKET_HEADER: u32 = 1;
+const GENERIC_INTERFACE_COUNTERS: u32 = 1;
+const HEADER_PROTOCOL_ETHERNET: u32 = 1;
+const IF_TYPE_ETHERNET: u32 = 6;
+// Output interface values for packets that didn't leave through one
+const OUTPUT_DISCARDED: u32 = 0x4000_0000;
+const DISCARD_UNKNOWN: u32 = 256;
+const DISCARD_ACL: u32 = 258;
+const OUTPUT_INTERNAL: u32 = 0x3fff_ffff;
+// Keeps a datagram under the usual 1500 byte MTU
+const MAX_DATAGRAM: usize = 1400;
+
+const ACTIONS: [XdpAction; 5] = [
+    XdpAction::ABORTED,
+    XdpAction::DROP,
+    XdpAction::PASS,
+    XdpAction::TX,
+    XdpAction::REDIRECT,
+];
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+// Opaque data is padded to 32 bits
+fn put_opaque(buf: &mut Vec<u8>, data: &[u8]) {
+    put_u32(buf, data.len() as u32);
+    buf.extend_from_slice(data);
+    buf.resize(buf.len() + (4 - data.len() % 4) % 4, 0);
+}
+
+// Wraps a sample or record body with its format and length
+fn put_structure(buf: &mut Vec<u8>, format: u32, body: &[u8]) {
+    put_u32(buf, format);
+    put_u32(buf, body.len() as u32);
+    buf.extend_from_slice(body);
+}
+
+fn output_interface(action: XdpAction, ifindex: u32) -> u32 {
+    match action {
+        XdpAction::DROP => OUTPUT_DISCARDED | DISCARD_ACL,
+        XdpAction::ABORTED => OUTPUT_DISCARDED | DISCARD_UNKNOWN,
+        XdpAction::PASS => OUTPUT_INTERNAL,
+        XdpAction::TX => ifindex,
+        // The devmap port isn't in the sample
+        XdpAction::REDIRECT => 0,
+    }
+}
+
+fn statistic(iface: &str, name: &str) -> u64 {
+    fs::read_to_string(format!("/sys/class/net/{}/statistics/{}", iface, name))
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+// Ingress as seen by the XDP program, per action
+#[derive(Default)]
+struct ActionCounters {
+    packets: [u64; 5],
+    bytes: [u64; 5],
+}
+
+fn read_counters(map: &PerCpuArray<MapRef, u64>) -> ActionCounters {
+    let mut counters = ActionCounters::default();
+    let total = |index: u32| {
+        map.get(&index, 0)
+            .map(|values| values.iter().sum())
+            .unwrap_or(0)
+    };
+    for action in ACTIONS.iter() {
+        let slot = *action as u32;
+        counters.packets[slot as usize] = total(slot * 2);
+        counters.bytes[slot as usize] = total(slot * 2 + 1);
+    }
+    counters
+}
+
+// Sequence numbers of the datagrams and of each kind of sample
+struct Agent {
+    address: Ipv4Addr,
+    iface: String,
+    ifindex: u32,
+    booted: Instant,
+    datagrams: u32,
+    flow_samples: u32,
+    counter_samples: u32,
+    // Packets the samples were taken from
+    pool: u32,
+    // Samples lost in the perf buffers
+    drops: u32,
+}
+
+impl Agent {
+    fn datagram(&mut self, samples: &[Vec<u8>]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(MAX_DATAGRAM);
+        put_u32(&mut buf, SFLOW_VERSION);
+        put_u32(&mut buf, ADDRESS_IPV4);
+        buf.extend_from_slice(&self.address.octets());
+        put_u32(&mut buf, 0);
+        self.datagrams = self.datagrams.wrapping_add(1);
+        put_u32(&mut buf, self.datagrams);
+        put_u32(&mut buf, self.booted.elapsed().as_millis() as u32);
+        put_u32(&mut buf, samples.len() as u32);
+        for sample in samples {
+            buf.extend_from_slice(sample);
+        }
+        buf
+    }
+
+    fn flow_sample(&mut self, sample: &SflowSample, header: &[u8]) -> Vec<u8> {
+        self.flow_samples = self.flow_samples.wrapping_add(1);
+        self.pool = self.pool.wrapping_add(sample.sample_rate);
+
+        let mut record = Vec::new();
+        put_u32(&mut record, HEADER_PROTOCOL_ETHERNET);
+        put_u32(&mut record, sample.frame_length);
+        // Nothing stripped, XDP doesn't see the FCS
+        put_u32(&mut record, 0);
+        put_opaque(&mut record, header);
+
+        let mut body = Vec::new();
+        put_u32(&mut body, self.flow_samples);
+        // Source id type 0 is an ifIndex
+        put_u32(&mut body, self.ifindex);
+        put_u32(&mut body, sample.sample_rate);
+        put_u32(&mut body, self.pool);
+        put_u32(&mut body, self.drops);
+        put_u32(&mut body, self.ifindex);
+        put_u32(&mut body, output_interface(sample.action, self.ifindex));
+        put_u32(&mut body, 1);
+        put_structure(&mut body, RAW_PACKET_HEADER, &record);
+
+        let mut buf = Vec::new();
+        put_structure(&mut buf, FLOW_SAMPLE, &body);
+        buf
+    }
+
+    fn counter_sample(&mut self, counters: &ActionCounters) -> Vec<u8> {
+        self.counter_samples = self.counter_samples.wrapping_add(1);
+        let discards = counters.packets[XdpAction::DROP as usize]
+            + counters.packets[XdpAction::ABORTED as usize];
+        let speed: u64 = fs::read_to_string(format!("/sys/class/net/{}/speed", self.iface))
+            .ok()
+            .and_then(|speed| speed.trim().parse().ok())
+            .map_or(0, |mbits: u64| mbits * 1_000_000);
+
+        let mut record = Vec::new();
+        put_u32(&mut record, self.ifindex);
+        put_u32(&mut record, IF_TYPE_ETHERNET);
+        put_u64(&mut record, speed);
+        // Direction unknown, admin and operational status up
+        put_u32(&mut record, 0);
+        put_u32(&mut record, 3);
+        put_u64(&mut record, counters.bytes.iter().sum());
+        put_u32(&mut record, counters.packets.iter().sum::<u64>() as u32);
+        // Multicast and broadcast packets aren't told apart
+        put_u32(&mut record, 0);
+        put_u32(&mut record, 0);
+        put_u32(&mut record, discards as u32);
+        put_u32(
+            &mut record,
+            counters.packets[XdpAction::ABORTED as usize] as u32,
+        );
+        put_u32(&mut record, 0);
+        // XDP only sees ingress, the kernel's statistics cover egress
+        put_u64(&mut record, statistic(&self.iface, "tx_bytes"));
+        put_u32(&mut record, statistic(&self.iface, "tx_packets") as u32);
+        put_u32(&mut record, 0);
+        put_u32(&mut record, 0);
+        put_u32(&mut record, statistic(&self.iface, "tx_dropped") as u32);
+        put_u32(&mut record, statistic(&self.iface, "tx_errors") as u32);
+        put_u32(&mut record, 0);
+
+        let mut body = Vec::new();
+        put_u32(&mut body, self.counter_samples);
+        put_u32(&mut body, self.ifindex);
+        put_u32(&mut body, 1);
+        put_structure(&mut body, GENERIC_INTERFACE_COUNTERS, &record);
+
+        let mut buf = Vec::new();
+        put_structure(&mut buf, COUNTER_SAMPLE, &body);
+        buf
+    }
+}
+
+// Splits samples into datagrams that fit the MTU
+fn datagrams(agent: &Mutex<Agent>, samples: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    let mut agent = agent.lock().unwrap();
+    let mut datagrams = Vec::new();
+    let mut batch = Vec::new();
+    let mut len = 0;
+    for sample in samples {
+        if len + sample.len() > MAX_DATAGRAM && !batch.is_empty() {
+            datagrams.push(agent.datagram(&batch));
+            batch.clear();
+            len = 0;
+        }
+        len += sample.len();
+        batch.push(sample);
+    }
+    if !batch.is_empty() {
+        datagrams.push(agent.datagram(&batch));
+    }
+    datagrams
+}
+
+// Samples 1 in rate packets with their headers and sends them with the
+// interface counters, every counter_interval, to the collector
+pub async fn start_sflow(
+    bpf: &Bpf,
+    iface: &str,
+    collector: SocketAddr,
+    rate: u32,
+    counter_interval: Duration,
+) -> Result<(), anyhow::Error> {
+    let address = interface_addresses(iface)?
+        .first()
+        .copied()
+        .unwrap_or(Ipv4Addr::UNSPECIFIED);
+    let bind: SocketAddr = match collector {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = Arc::new(UdpSocket::bind(bind).await?);
+    let agent = Arc::new(Mutex::new(Agent {
+        address,
+        iface: iface.to_string(),
+        ifindex: if_nametoindex(iface)?,
+        booted: Instant::now(),
+        datagrams: 0,
+        flow_samples: 0,
+        counter_samples: 0,
+        pool: 0,
+        drops: 0,
+    }));
+
+    let mut perf_array = AsyncPerfEventArray::try_from(bpf.map_mut("SFLOW_SAMPLES")?)?;
+    for cpu_id in online_cpus()? {
+        let mut per_cpu_buffer = perf_array.open(cpu_id, None)?;
+        let agent = agent.clone();
+        let socket = socket.clone();
+        task::spawn(async move {
+            let mut buffers = (0..10)
+                .map(|_| BytesMut::with_capacity(1024))
+                .collect::<Vec<_>>();
+            loop {
+                let events = per_cpu_buffer.read_events(&mut buffers).await.unwrap();
+                let samples: Vec<_> = {
+                    let mut agent = agent.lock().unwrap();
+                    agent.drops = agent.drops.wrapping_add(events.lost as u32);
+                    buffers
+                        .iter()
+                        .take(events.read)
+                        .map(|buf| {
+                            let sample =
+                                unsafe { buf.as_ptr().cast::<SflowSample>().read_unaligned() };
+                            let start = mem::size_of::<SflowSample>();
+                            let end = (start + sample.header_length as usize).min(buf.len());
+                            agent.flow_sample(&sample, &buf[start..end])
+                        })
+                        .collect()
+                };
+                for datagram in datagrams(&agent, samples) {
+                    if let Err(e) = socket.send_to(&datagram, collector).await {
+                        warn!("sFlow to {}: {}", collector, e);
+                    }
+                }
+            }
+        });
+    }
+
+    let counters = PerCpuArray::try_from(bpf.map("ACTION_COUNTERS")?)?;
+    task::spawn(async move {
+        let mut ticker = tokio::time::interval(counter_interval);
+        loop {
+            ticker.tick().await;
+            let sample = agent
+                .lock()
+                .unwrap()
+                .counter_sample(&read_counters(&counters));
+            for datagram in datagrams(&agent, vec![sample]) {
+                if let Err(e) = socket.send_to(&datagram, collector).await {
+                    warn!("sFlow to {}: {}", collector, e);
+                }
+            }
+        }
+    });
+
+    // Sampling starts once the samples are read
+    let mut config: Array<_, u32> = Array::try_from(bpf.map_mut("CONFIG")?)?;
+    config.set(CONFIG_SFLOW_RATE, rate, 0)?;
+    info!(
+        "sFlow to {}, sampling 1 in {} packets, counters every {}s",
+        collector,
+        rate,
+        counter_interval.as_secs()
+    );
+    Ok(())
+}
@@ -0,0 +1,131 @@
+use aya::maps::Map;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_MAP_DELETE_ELEM: libc::c_long = 3;
+const BPF_OBJ_PIN: libc::c_long = 6;
+
+pub const BPF_MAP_TYPE_HASH: u32 = 1;
+pub const BPF_MAP_TYPE_ARRAY_OF_MAPS: u32 = 12;
+
+// bpf_attr as used by the map element commands
+#[repr(C)]
+struct MapElemAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+// bpf_attr as used by BPF_MAP_CREATE, the fields after inner_map_fd are left zero
+#[repr(C)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+    inner_map_fd: u32,
+}
+
+// bpf_attr as used by BPF_OBJ_PIN
+#[repr(C)]
+struct ObjPinAttr {
+    pathname: u64,
+    bpf_fd: u32,
+    file_flags: u32,
+}
+
+fn bpf<T>(cmd: libc::c_long, attr: &T) -> Result<libc::c_long, io::Error> {
+    let ret = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr as *const T, mem::size_of::<T>()) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret)
+}
+
+fn fd_update<K, V>(fd: RawFd, key: &K, value: &V, flags: u64) -> Result<(), io::Error> {
+    let attr = MapElemAttr {
+        map_fd: fd as u32,
+        _pad: 0,
+        key: key as *const K as u64,
+        value: value as *const V as u64,
+        flags,
+    };
+    bpf(BPF_MAP_UPDATE_ELEM, &attr).map(|_| ())
+}
+
+// Update for the map types aya has no wrapper for yet, done with the bpf syscall directly
+pub fn map_update<K, V>(map: &Map, key: &K, value: &V, flags: u64) -> Result<(), io::Error> {
+    fd_update(map.as_raw_fd(), key, value, flags)
+}
+
+// A map created outside of the loader, closed on drop. The kernel frees it once
+// no program or outer map refers to it either
+pub struct MapFd(RawFd);
+
+impl MapFd {
+    pub fn create(
+        map_type: u32,
+        key_size: usize,
+        value_size: usize,
+        max_entries: u32,
+        inner: Option<&MapFd>,
+    ) -> Result<MapFd, io::Error> {
+        let attr = MapCreateAttr {
+            map_type,
+            key_size: key_size as u32,
+            value_size: value_size as u32,
+            max_entries,
+            map_flags: 0,
+            inner_map_fd: inner.map_or(0, |inner| inner.0 as u32),
+        };
+        bpf(BPF_MAP_CREATE, &attr).map(|fd| MapFd(fd as RawFd))
+    }
+
+    pub fn update<K, V>(&self, key: &K, value: &V, flags: u64) -> Result<(), io::Error> {
+        fd_update(self.0, key, value, flags)
+    }
+
+    pub fn delete<K>(&self, key: &K) -> Result<(), io::Error> {
+        let attr = MapElemAttr {
+            map_fd: self.0 as u32,
+            _pad: 0,
+            key: key as *const K as u64,
+            value: 0,
+            flags: 0,
+        };
+        bpf(BPF_MAP_DELETE_ELEM, &attr).map(|_| ())
+    }
+
+    // Pins the map on a bpf filesystem so a loader can pick it up by path
+    pub fn pin(&self, path: &Path) -> Result<(), io::Error> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let attr = ObjPinAttr {
+            pathname: path.as_ptr() as u64,
+            bpf_fd: self.0 as u32,
+            file_flags: 0,
+        };
+        bpf(BPF_OBJ_PIN, &attr).map(|_| ())
+    }
+}
+
+impl AsRawFd for MapFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for MapFd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
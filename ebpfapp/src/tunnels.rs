@@ -0,0 +1,28 @@
+use aya::maps::Array;
+use aya::Bpf;
+use ebpfapp_common::{TunnelType, CONFIG_TUNNELS};
+use log::info;
+use std::convert::TryFrom;
+
+use crate::parser::ParserToString;
+
+pub fn parse_tunnel(s: &str) -> Result<TunnelType, String> {
+    Ok(match s {
+        "ipip" => TunnelType::IPIP,
+        "gre" => TunnelType::GRE,
+        "vxlan" => TunnelType::VXLAN,
+        _ => return Err(format!("unknown tunnel type {}", s)),
+    })
+}
+
+pub fn load_tunnels(bpf: &Bpf, tunnels: &[TunnelType]) -> Result<(), anyhow::Error> {
+    let mut config: Array<_, u32> = Array::try_from(bpf.map_mut("CONFIG")?)?;
+    let mask = tunnels.iter().fold(0, |mask, tunnel| mask | tunnel.mask());
+    config.set(CONFIG_TUNNELS, mask, 0)?;
+
+    if !tunnels.is_empty() {
+        let names: Vec<_> = tunnels.iter().map(|tunnel| tunnel.to_str()).collect();
+        info!("Inspecting tunnels: {}", names.join(", "));
+    }
+    Ok(())
+}
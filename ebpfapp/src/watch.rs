@@ -0,0 +1,213 @@
+use anyhow::bail;
+use log::{info, warn};
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::net::Ipv4Addr;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command as Process, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use crate::Command;
+
+fn default_window() -> u64 {
+    600
+}
+
+fn default_block() -> u64 {
+    3600
+}
+
+fn regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+    let regex = String::deserialize(deserializer)?;
+    Regex::new(&regex).map_err(serde::de::Error::custom)
+}
+
+// Blocks the addresses that show up in too many matching lines of a log, e.g.
+//
+// [[watch]]
+// name = "sshd"
+// journal = "ssh.service"
+// regex = 'Failed password for .* from (?P<ip>\d+\.\d+\.\d+\.\d+)'
+// failures = 5
+#[derive(Debug, Clone, Deserialize)]
+pub struct WatchConfig {
+    pub name: String,
+    // Either a file to follow or a journald unit
+    pub file: Option<PathBuf>,
+    pub journal: Option<String>,
+    // The address is the "ip" group, or the first group without one
+    #[serde(deserialize_with = "regex")]
+    pub regex: Regex,
+    pub failures: usize,
+    // Seconds the failures are counted over
+    #[serde(default = "default_window")]
+    pub window: u64,
+    // Seconds the address stays blocked
+    #[serde(default = "default_block")]
+    pub block: u64,
+}
+
+impl WatchConfig {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.file.is_some() == self.journal.is_some() {
+            bail!("a watch needs either a file or a journal unit");
+        }
+        if self.regex.captures_len() < 2 {
+            bail!("the regex has no group capturing the address");
+        }
+        if self.failures == 0 {
+            bail!("failures must be at least 1");
+        }
+        Ok(())
+    }
+
+    fn address(&self, line: &str) -> Option<Ipv4Addr> {
+        let captures = self.regex.captures(line)?;
+        captures
+            .name("ip")
+            .or_else(|| captures.get(1))?
+            .as_str()
+            .parse()
+            .ok()
+    }
+}
+
+// Failures of each address within the window
+struct Failures {
+    window: Duration,
+    threshold: usize,
+    seen: HashMap<Ipv4Addr, VecDeque<Instant>>,
+}
+
+impl Failures {
+    // Returns how many failures the address had once it crosses the threshold
+    fn record(&mut self, address: Ipv4Addr, now: Instant) -> Option<usize> {
+        let window = self.window;
+        let failures = self.seen.entry(address).or_default();
+        failures.push_back(now);
+        while let Some(first) = failures.front() {
+            if now.duration_since(*first) < window {
+                break;
+            }
+            failures.pop_front();
+        }
+        if failures.len() < self.threshold {
+            return None;
+        }
+        let count = failures.len();
+        self.seen.remove(&address);
+        // Addresses that stopped failing would otherwise stay around
+        if self.seen.len() > 10_000 {
+            self.seen.retain(|_, failures| {
+                matches!(failures.back(), Some(last) if now.duration_since(*last) < window)
+            });
+        }
+        Some(count)
+    }
+}
+
+// Lines appended to the file, reopened when it is rotated or truncated
+fn follow_file(path: &Path, mut line: impl FnMut(&str) -> bool) -> Result<(), anyhow::Error> {
+    let mut file = File::open(path)?;
+    let mut inode = file.metadata()?.ino();
+    let mut position = file.seek(SeekFrom::End(0))?;
+    let mut reader = BufReader::new(file);
+    let mut buf = String::new();
+    loop {
+        buf.clear();
+        let read = reader.read_line(&mut buf)?;
+        // A partial line is read again once it is complete
+        if read > 0 && buf.ends_with('\n') {
+            position += read as u64;
+            if !line(buf.trim_end()) {
+                return Ok(());
+            }
+            continue;
+        }
+        reader.seek(SeekFrom::Start(position))?;
+        thread::sleep(Duration::from_secs(1));
+
+        let rotated = match std::fs::metadata(path) {
+            Ok(metadata) => metadata.ino() != inode || metadata.len() < position,
+            Err(_) => false,
+        };
+        if rotated {
+            let file = File::open(path)?;
+            inode = file.metadata()?.ino();
+            position = 0;
+            reader = BufReader::new(file);
+        }
+    }
+}
+
+// New entries of the unit's journal
+fn follow_journal(unit: &str, mut line: impl FnMut(&str) -> bool) -> Result<(), anyhow::Error> {
+    let mut child = Process::new("journalctl")
+        .args(["--follow", "--lines=0", "--output=cat", "--unit", unit])
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take().unwrap();
+    for entry in BufReader::new(stdout).lines() {
+        if !line(&entry?) {
+            break;
+        }
+    }
+    let _ = child.kill();
+    Ok(())
+}
+
+// Follows each log on its own thread and blocks the addresses that fail too often
+pub fn spawn_watchers(watches: &[WatchConfig], tx: &mpsc::Sender<Command>) {
+    for watch in watches.iter().cloned() {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            let mut failures = Failures {
+                window: Duration::from_secs(watch.window),
+                threshold: watch.failures,
+                seen: HashMap::new(),
+            };
+            let ttl = Duration::from_secs(watch.block);
+            let line = |line: &str| {
+                let address = match watch.address(line) {
+                    Some(address) => address,
+                    None => return true,
+                };
+                let count = match failures.record(address, Instant::now()) {
+                    Some(count) => count,
+                    None => return true,
+                };
+                warn!(
+                    "Watch {}: {} failed {} times in {}s, blocking for {}s",
+                    watch.name, address, count, watch.window, watch.block
+                );
+                // Stops following once process_actions is gone
+                tx.blocking_send(Command::Block {
+                    ip: address,
+                    vlan: None,
+                    ttl: Some(ttl),
+                })
+                .is_ok()
+            };
+            let followed = match (&watch.file, &watch.journal) {
+                (Some(path), _) => {
+                    info!("Watch {}: following {}", watch.name, path.display());
+                    follow_file(path, line)
+                }
+                (None, Some(unit)) => {
+                    info!("Watch {}: following journal of {}", watch.name, unit);
+                    follow_journal(unit, line)
+                }
+                (None, None) => Ok(()),
+            };
+            if let Err(e) = followed {
+                warn!("Watch {} stopped: {:#}", watch.name, e);
+            }
+        });
+    }
+}
@@ -0,0 +1,511 @@
+use anyhow::{bail, Context};
+use aya::Bpf;
+use ebpfapp_common::XSK_QUEUES;
+use log::{info, warn};
+use nix::net::if_::if_nametoindex;
+use std::fs;
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use crate::lb::parse_mac;
+use crate::sys;
+
+// linux/if_xdp.h, defined here as not every libc release has them
+const AF_XDP: libc::c_int = 44;
+const SOL_XDP: libc::c_int = 283;
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x100000000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x180000000;
+
+// linux/if_tun.h
+const TUNSETIFF: libc::c_ulong = 0x400454ca;
+const IFF_TAP: libc::c_short = 0x0002;
+const IFF_NO_PI: libc::c_short = 0x1000;
+
+// Every ring can hold every frame, so a frame always has somewhere to go
+const NUM_FRAMES: u32 = 4096;
+const FRAME_SIZE: u32 = 2048;
+const UMEM_LEN: usize = (NUM_FRAMES * FRAME_SIZE) as usize;
+const POLL_TIMEOUT_MS: libc::c_int = 1000;
+
+#[repr(C)]
+struct UmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+    _pad: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct RingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MmapOffsets {
+    rx: RingOffset,
+    tx: RingOffset,
+    fill: RingOffset,
+    completion: RingOffset,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+// struct ifreq with the flags member of its union
+#[repr(C)]
+struct Ifreq {
+    name: [u8; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+#[repr(C)]
+struct SockaddrXdp {
+    family: u16,
+    flags: u16,
+    ifindex: u32,
+    queue_id: u32,
+    shared_umem_fd: u32,
+}
+
+// What to do with a packet once it has been inspected
+#[derive(Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Drop,
+    // Hand the frame to the kernel stack through the TAP interface
+    Reinject,
+}
+
+// Single producer, single consumer ring shared with the kernel
+struct Ring<T> {
+    area: *mut libc::c_void,
+    len: usize,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    descs: *mut T,
+    mask: u32,
+}
+
+impl<T: Copy> Ring<T> {
+    fn map(fd: RawFd, offset: &RingOffset, pgoff: libc::off_t) -> io::Result<Ring<T>> {
+        let len = offset.desc as usize + NUM_FRAMES as usize * mem::size_of::<T>();
+        let area = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                pgoff,
+            )
+        };
+        if area == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let at = |off: u64| unsafe { (area as *mut u8).add(off as usize) };
+        Ok(Ring {
+            area,
+            len,
+            producer: at(offset.producer) as *const AtomicU32,
+            consumer: at(offset.consumer) as *const AtomicU32,
+            descs: at(offset.desc) as *mut T,
+            mask: NUM_FRAMES - 1,
+        })
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        unsafe { &*self.consumer }
+    }
+
+    // Takes every entry the kernel has produced
+    fn consume(&self) -> Vec<T> {
+        let consumer = self.consumer().load(Ordering::Relaxed);
+        let producer = self.producer().load(Ordering::Acquire);
+        let entries = (0..producer.wrapping_sub(consumer))
+            .map(|i| {
+                let index = consumer.wrapping_add(i) & self.mask;
+                unsafe { *self.descs.add(index as usize) }
+            })
+            .collect();
+        self.consumer().store(producer, Ordering::Release);
+        entries
+    }
+
+    // Hands entries to the kernel, returns how many fit
+    fn produce(&self, entries: &[T]) -> usize {
+        let producer = self.producer().load(Ordering::Relaxed);
+        let consumer = self.consumer().load(Ordering::Acquire);
+        let free = NUM_FRAMES - producer.wrapping_sub(consumer);
+        let count = entries.len().min(free as usize);
+        for (i, entry) in entries.iter().take(count).enumerate() {
+            let index = producer.wrapping_add(i as u32) & self.mask;
+            unsafe { *self.descs.add(index as usize) = *entry };
+        }
+        self.producer()
+            .store(producer.wrapping_add(count as u32), Ordering::Release);
+        count
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.area, self.len) };
+    }
+}
+
+// An AF_XDP socket bound to one receive queue, with its own packet buffer (UMEM)
+pub struct XskSocket {
+    fd: RawFd,
+    queue: u32,
+    umem: *mut u8,
+    rx: Ring<XdpDesc>,
+    fill: Ring<u64>,
+    // A UMEM needs one, but nothing is transmitted through the socket
+    _completion: Ring<u64>,
+}
+
+// The mappings are only touched by the thread that owns the socket
+unsafe impl Send for XskSocket {}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn set_option<T>(fd: RawFd, name: libc::c_int, value: &T) -> io::Result<()> {
+    check(unsafe {
+        libc::setsockopt(
+            fd,
+            SOL_XDP,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    })
+}
+
+impl XskSocket {
+    pub fn new(ifindex: u32, queue: u32) -> io::Result<XskSocket> {
+        let fd = unsafe { libc::socket(AF_XDP, libc::SOCK_RAW, 0) };
+        check(fd)?;
+        let umem = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                UMEM_LEN,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if umem == libc::MAP_FAILED {
+            let e = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+        let socket = Self::setup(fd, umem as *mut u8, ifindex, queue);
+        if socket.is_err() {
+            unsafe {
+                libc::close(fd);
+                libc::munmap(umem, UMEM_LEN);
+            }
+        }
+        socket
+    }
+
+    fn setup(fd: RawFd, umem: *mut u8, ifindex: u32, queue: u32) -> io::Result<XskSocket> {
+        let reg = UmemReg {
+            addr: umem as u64,
+            len: UMEM_LEN as u64,
+            chunk_size: FRAME_SIZE,
+            headroom: 0,
+            flags: 0,
+            _pad: 0,
+        };
+        set_option(fd, XDP_UMEM_REG, &reg)?;
+        for ring in &[XDP_UMEM_FILL_RING, XDP_UMEM_COMPLETION_RING, XDP_RX_RING] {
+            set_option(fd, *ring, &NUM_FRAMES)?;
+        }
+
+        let mut offsets = MmapOffsets::default();
+        let mut optlen = mem::size_of::<MmapOffsets>() as libc::socklen_t;
+        check(unsafe {
+            libc::getsockopt(
+                fd,
+                SOL_XDP,
+                XDP_MMAP_OFFSETS,
+                &mut offsets as *mut MmapOffsets as *mut libc::c_void,
+                &mut optlen,
+            )
+        })?;
+        let rx = Ring::map(fd, &offsets.rx, XDP_PGOFF_RX_RING)?;
+        let fill = Ring::map(fd, &offsets.fill, XDP_UMEM_PGOFF_FILL_RING)?;
+        let completion = Ring::map(fd, &offsets.completion, XDP_UMEM_PGOFF_COMPLETION_RING)?;
+
+        // Every frame starts out waiting for a packet
+        let frames: Vec<u64> = (0..NUM_FRAMES).map(|i| (i * FRAME_SIZE) as u64).collect();
+        fill.produce(&frames);
+
+        let addr = SockaddrXdp {
+            family: AF_XDP as u16,
+            flags: 0,
+            ifindex,
+            queue_id: queue,
+            shared_umem_fd: 0,
+        };
+        check(unsafe {
+            libc::bind(
+                fd,
+                &addr as *const SockaddrXdp as *const libc::sockaddr,
+                mem::size_of::<SockaddrXdp>() as libc::socklen_t,
+            )
+        })?;
+
+        Ok(XskSocket {
+            fd,
+            queue,
+            umem,
+            rx,
+            fill,
+            _completion: completion,
+        })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    fn frame(&self, desc: &XdpDesc) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.umem.add(desc.addr as usize), desc.len as usize) }
+    }
+
+    // Hands every received packet to inspect, then drops or reinjects it. Never returns
+    // unless the socket fails
+    pub fn run<F: FnMut(u32, &[u8]) -> Verdict>(
+        &mut self,
+        tap: Option<&Tap>,
+        mut inspect: F,
+    ) -> io::Result<()> {
+        loop {
+            let mut pollfd = libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            check(unsafe { libc::poll(&mut pollfd, 1, POLL_TIMEOUT_MS) })?;
+
+            let mut free = Vec::new();
+            for desc in self.rx.consume() {
+                let frame = self.frame(&desc);
+                if let (Verdict::Reinject, Some(tap)) = (inspect(self.queue, frame), tap) {
+                    if let Err(e) = tap.reinject(frame) {
+                        warn!("Packet not reinjected: {}", e);
+                    }
+                }
+                // Written to the TAP, the frame can take a packet again
+                free.push(desc.addr);
+            }
+            // Frame addresses can carry an offset into the frame, the fill ring wants the start
+            let free: Vec<u64> = free
+                .iter()
+                .map(|addr| addr - addr % FRAME_SIZE as u64)
+                .collect();
+            self.fill.produce(&free);
+        }
+    }
+}
+
+impl Drop for XskSocket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+            libc::munmap(self.umem as *mut libc::c_void, UMEM_LEN);
+        }
+    }
+}
+
+// A TAP interface reinjected packets are written to. The kernel receives what is
+// written as if it arrived on the TAP, so the packets go through the stack
+pub struct Tap {
+    fd: RawFd,
+    mac: [u8; 6],
+}
+
+impl Tap {
+    // Creates the TAP, or attaches to it when it exists, and brings it up
+    pub fn open(name: &str) -> Result<Tap, anyhow::Error> {
+        if name.is_empty() || name.len() >= libc::IFNAMSIZ {
+            bail!("invalid interface name {}", name);
+        }
+        let mut ifreq = Ifreq {
+            name: [0; libc::IFNAMSIZ],
+            flags: IFF_TAP | IFF_NO_PI,
+            _pad: [0; 22],
+        };
+        ifreq.name[..name.len()].copy_from_slice(name.as_bytes());
+        let fd = unsafe {
+            libc::open(
+                b"/dev/net/tun\0".as_ptr() as *const libc::c_char,
+                libc::O_RDWR | libc::O_CLOEXEC,
+            )
+        };
+        check(fd).context("failed to open /dev/net/tun")?;
+        let mut tap = Tap { fd, mac: [0; 6] };
+        check(unsafe { libc::ioctl(fd, TUNSETIFF as _, &mut ifreq as *mut Ifreq) })
+            .with_context(|| format!("failed to attach to TAP {}", name))?;
+        set_up(&mut ifreq).with_context(|| format!("failed to bring {} up", name))?;
+        let address = fs::read_to_string(format!("/sys/class/net/{}/address", name))
+            .with_context(|| format!("failed to read the MAC of {}", name))?;
+        tap.mac = parse_mac(address.trim()).map_err(anyhow::Error::msg)?;
+        Ok(tap)
+    }
+
+    // Addresses the frame to the TAP, or the stack would take it for a packet to
+    // another host, and writes it
+    fn reinject(&self, frame: &[u8]) -> io::Result<()> {
+        let mut frame = frame.to_vec();
+        if frame.len() >= self.mac.len() {
+            frame[..self.mac.len()].copy_from_slice(&self.mac);
+        }
+        let written =
+            unsafe { libc::write(self.fd, frame.as_ptr() as *const libc::c_void, frame.len()) };
+        if written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Tap {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+fn set_up(ifreq: &mut Ifreq) -> io::Result<()> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    check(fd)?;
+    let mut result =
+        check(unsafe { libc::ioctl(fd, libc::SIOCGIFFLAGS as _, ifreq as *mut Ifreq) });
+    if result.is_ok() {
+        ifreq.flags |= libc::IFF_UP as libc::c_short;
+        result = check(unsafe { libc::ioctl(fd, libc::SIOCSIFFLAGS as _, ifreq as *mut Ifreq) });
+    }
+    unsafe { libc::close(fd) };
+    result
+}
+
+// The default inspection, logs the addresses of every IPv4 packet and passes it
+// on. This is the place to hook deeper inspection in
+fn log_packet(queue: u32, frame: &[u8]) -> Verdict {
+    const ETH_P_IP: [u8; 2] = [0x08, 0x00];
+    const ETH_HDR_LEN: usize = 14;
+    if frame.len() >= ETH_HDR_LEN + 20 && frame[12..14] == ETH_P_IP {
+        let ip = &frame[ETH_HDR_LEN..];
+        let source = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+        let destination = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+        info!(
+            "XSK queue {}: SRC {}, DST {}, PROTO {}, {} bytes",
+            queue,
+            source,
+            destination,
+            ip[9],
+            frame.len()
+        );
+        Verdict::Reinject
+    } else {
+        // Only IPv4 sources have inspect rules, anything else is left out
+        info!("XSK queue {}: {} bytes", queue, frame.len());
+        Verdict::Drop
+    }
+}
+
+// Receive queues of the interface, each has an rx-N directory in sysfs
+fn rx_queues(iface: &str) -> Result<u32, anyhow::Error> {
+    let path = format!("/sys/class/net/{}/queues", iface);
+    let queues = fs::read_dir(&path)
+        .with_context(|| format!("failed to list the queues of {}", iface))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("rx-"))
+        .count();
+    Ok(queues as u32)
+}
+
+// Binds a socket to each receive queue of the interface, or the first queues when
+// given, and registers it in the XSK_SOCKETS map so INSPECT rules deliver their
+// packets to it. Packets on queues without a socket skip inspection. Inspected
+// packets are written to the TAP when one is named, and dropped otherwise
+pub fn spawn_consumers(
+    bpf: &Bpf,
+    iface: &str,
+    queues: Option<u32>,
+    reinject: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    let rx_queues = rx_queues(iface)?;
+    let queues = queues.unwrap_or(rx_queues);
+    if queues > XSK_QUEUES {
+        anyhow::bail!(
+            "AF_XDP sockets on {} queues, at most {} are supported",
+            queues,
+            XSK_QUEUES
+        );
+    }
+    if queues < rx_queues {
+        warn!(
+            "{} has {} receive queues, inspect rules only apply on the first {}",
+            iface, rx_queues, queues
+        );
+    }
+    let tap = match reinject {
+        Some(name) => {
+            info!("Reinjecting inspected packets through {}", name);
+            Some(Arc::new(Tap::open(name)?))
+        }
+        None => None,
+    };
+    let map = bpf.map_mut("XSK_SOCKETS")?;
+    let ifindex = if_nametoindex(iface)?;
+    for queue in 0..queues {
+        let mut socket = XskSocket::new(ifindex, queue)
+            .with_context(|| format!("failed to open an AF_XDP socket on queue {}", queue))?;
+        sys::map_update(&map, &queue, &(socket.fd() as u32), 0)?;
+        info!("AF_XDP socket on {} queue {}", iface, queue);
+        let tap = tap.clone();
+        thread::spawn(move || {
+            if let Err(e) = socket.run(tap.as_deref(), log_packet) {
+                warn!("AF_XDP socket on queue {} failed: {}", queue, e);
+            }
+        });
+    }
+    Ok(())
+}